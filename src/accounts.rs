@@ -0,0 +1,238 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use rand::RngCore;
+use ring::hmac;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::models::now_millis;
+use crate::protocol::ClientHello;
+
+const NAME_LENGTH : std::ops::RangeInclusive<usize> = 3..=20;
+const MIN_PASSWORD_LENGTH : usize = 8;
+const SALT_LENGTH : usize = 16;
+// Argon2id with memory of 19 MiB and two passes
+const HASH_MEMORY : u32 = 19 * 1024;
+const HASH_PASSES : u32 = 2;
+// Unknown names are verified against this hash so that login takes
+// as long as for existing accounts. Hashed with the cost above.
+const DUMMY_HASH : &str = "$argon2id$v=19$m=19456,t=2,p=1$XU2j2b3XEvhZSXpFGoc9IQ$U7zba6pJt72mm+gEfKOvXGRJt5ySyPrCUC34CocZ5RY";
+
+// Registered player, guests have none
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct Account {
+    // Never changes, unlike name
+    pub id: String,
+    pub name: String,
+}
+
+// Body of register and login routes
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct Credentials {
+    pub name: String,
+    pub password: String,
+}
+
+// Reply of register and login routes
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct Session {
+    pub account: Account,
+    // Given as `auth` query of websocket routes
+    pub token: String,
+    // Milliseconds since unix epoch
+    pub expires: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Invalid(String),
+    NameTaken,
+    WrongCredentials,
+    Storage(String),
+}
+
+impl AuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::Invalid(_) => StatusCode::BAD_REQUEST,
+            AuthError::NameTaken => StatusCode::CONFLICT,
+            AuthError::WrongCredentials => StatusCode::UNAUTHORIZED,
+            AuthError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Invalid(msg) => write!(f, "{}", msg),
+            AuthError::NameTaken => write!(f, "Name is already taken"),
+            // Same message for unknown name so that names can't be probed
+            AuthError::WrongCredentials => write!(f, "Wrong name or password"),
+            AuthError::Storage(msg) => write!(f, "Storage error : {}", msg),
+        }
+    }
+}
+
+// Where accounts and their password hashes are kept
+pub trait AccountStore: Send + Sync {
+    // Name is unique regardless of case
+    fn create(&self, name: &str, password_hash: &str) -> Result<Account, AuthError>;
+
+    // Account and its password hash
+    fn find_by_name(&self, name: &str) -> Result<Option<(Account, String)>, AuthError>;
+}
+
+// What is signed into a token
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    id: String,
+    name: String,
+    exp: u64,
+}
+
+#[derive(Clone)]
+pub struct Accounts {
+    store: Arc<dyn AccountStore>,
+    key: Arc<hmac::Key>,
+    token_ttl: u64,
+}
+
+impl Accounts {
+    // Tokens are signed with secret, thus they stay valid
+    // across restarts only when secret is configured.
+    pub fn new(store: Arc<dyn AccountStore>, secret: &[u8], token_ttl: u64) -> Self {
+        Self {
+            store,
+            key: Arc::new(hmac::Key::new(hmac::HMAC_SHA256, secret)),
+            token_ttl,
+        }
+    }
+
+    // Hashing is slow on purpose, call it off the async runtime
+    pub fn register(&self, credentials: &Credentials) -> Result<Session, AuthError> {
+        let name = credentials.name.trim();
+        if !NAME_LENGTH.contains(&name.chars().count()) {
+            return Err(AuthError::Invalid(format!(
+                "Name should be between {} and {} characters", NAME_LENGTH.start(), NAME_LENGTH.end()
+            )));
+        }
+        if !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+            return Err(AuthError::Invalid("Name can only have letters, digits, _ and -".to_string()));
+        }
+        if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AuthError::Invalid(format!("Password should be at least {} characters", MIN_PASSWORD_LENGTH)));
+        }
+
+        let account = self.store.create(name, &hash_password(&credentials.password)?)?;
+        Ok(self.issue(account))
+    }
+
+    pub fn login(&self, credentials: &Credentials) -> Result<Session, AuthError> {
+        let (account, hash) = match self.store.find_by_name(credentials.name.trim())? {
+            Some((account, hash)) => (Some(account), hash),
+            None => (None, DUMMY_HASH.to_string()),
+        };
+        match (argon2::verify_encoded(&hash, credentials.password.as_bytes()), account) {
+            (Ok(true), Some(account)) => Ok(self.issue(account)),
+            (Ok(_), _) => Err(AuthError::WrongCredentials),
+            (Err(err), _) => Err(AuthError::Storage(err.to_string())),
+        }
+    }
+
+    // Token is payload and its signature, both base64
+    pub fn issue(&self, account: Account) -> Session {
+        let expires = now_millis() + self.token_ttl * 1000;
+        let claims = Claims {
+            id: account.id.clone(),
+            name: account.name.clone(),
+            exp: expires,
+        };
+        let payload = base64::encode_config(
+            serde_json::to_vec(&claims).expect("Failed to encode claims"),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let signature = base64::encode_config(hmac::sign(&self.key, payload.as_bytes()), base64::URL_SAFE_NO_PAD);
+        Session {
+            account,
+            token: format!("{}.{}", payload, signature),
+            expires,
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Account, String> {
+        let invalid = || "Invalid auth token".to_string();
+        let mut split = token.splitn(2, '.');
+        let payload = split.next().ok_or_else(invalid)?;
+        let signature = split.next()
+            .and_then(|signature| base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok())
+            .ok_or_else(invalid)?;
+        hmac::verify(&self.key, payload.as_bytes(), &signature).map_err(|_| invalid())?;
+
+        let claims = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()
+            .and_then(|payload| serde_json::from_slice::<Claims>(&payload).ok())
+            .ok_or_else(invalid)?;
+        if claims.exp <= now_millis() {
+            return Err("Auth token has expired".to_string());
+        }
+        Ok(Account {
+            id: claims.id,
+            name: claims.name,
+        })
+    }
+}
+
+// Random secret for servers that don't configure one
+pub fn random_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+fn hash_password(password: &str) -> Result<String, AuthError> {
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        mem_cost: HASH_MEMORY,
+        time_cost: HASH_PASSES,
+        ..argon2::Config::default()
+    };
+    argon2::hash_encoded(password.as_bytes(), &salt, &config)
+        .map_err(|err| AuthError::Storage(err.to_string()))
+}
+
+pub async fn register_handler(credentials: Credentials, accounts: Accounts) -> Result<impl warp::Reply, Infallible> {
+    Ok(session_reply(tokio::task::spawn_blocking(move || accounts.register(&credentials)).await, StatusCode::CREATED))
+}
+
+pub async fn login_handler(credentials: Credentials, accounts: Accounts) -> Result<impl warp::Reply, Infallible> {
+    Ok(session_reply(tokio::task::spawn_blocking(move || accounts.login(&credentials)).await, StatusCode::OK))
+}
+
+fn session_reply(result: Result<Result<Session, AuthError>, tokio::task::JoinError>, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+    let result = result.unwrap_or_else(|err| Err(AuthError::Storage(err.to_string())));
+    match result {
+        Ok(session) => warp::reply::with_status(warp::reply::json(&session), status),
+        Err(err) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": err.to_string() })),
+            err.status(),
+        ),
+    }
+}
+
+pub fn with_accounts(accounts: Accounts) -> impl Filter<Extract = (Accounts,), Error = Infallible> + Clone {
+    warp::any().map(move || accounts.clone())
+}
+
+// Hello query of websocket routes with its auth token verified
+pub fn client_hello(accounts: Accounts) -> impl Filter<Extract = (ClientHello,), Error = warp::Rejection> + Clone {
+    warp::query::<ClientHello>()
+        .and(with_accounts(accounts))
+        .map(|mut hello: ClientHello, accounts: Accounts| {
+            hello.account = hello.auth.as_deref().map(|token| accounts.verify(token));
+            hello
+        })
+}
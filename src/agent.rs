@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use crate::models::{Game, GameState, PlayerAction, ServerResponse, ResponseValue, UserRequest};
+use crate::protocol::ClientInfo;
+use crate::outbox::Outbox;
+use crate::accounts::Account;
+
+// Every seat of a game is driven by an agent.
+// Game pushes events to agent through send_event
+// and actions of agent are played by the room for the seat.
+// Thus websocket client, bot, replay or test harness
+// can sit in a game without any changes to game code.
+pub trait PlayerAgent: Send + Sync {
+    fn send_event(&self, res: &ServerResponse);
+
+    // Actions that agent produces by itself.
+    // Room takes the receiver once when agent is seated.
+    // Websocket client has none because socket handler reads its frames.
+    fn take_actions(&mut self) -> Option<mpsc::UnboundedReceiver<UserRequest>> {
+        None
+    }
+
+    // Agent has missed game updates and needs a snapshot
+    fn take_stale(&self) -> bool {
+        false
+    }
+
+    // Account of the player, none for guests and bots
+    fn account(&self) -> Option<Account> {
+        None
+    }
+}
+
+// Agent for websocket client, event is encoded in client's wire format
+pub struct WebSocketAgent {
+    sender: Outbox,
+    client: ClientInfo,
+}
+
+impl WebSocketAgent {
+    pub fn new(sender: Outbox, client: ClientInfo) -> Self {
+        Self {
+            sender,
+            client,
+        }
+    }
+}
+
+impl PlayerAgent for WebSocketAgent {
+    fn send_event(&self, res: &ServerResponse) {
+        // Older client doesn't understand newer responses
+        self.client.send(&self.sender, res);
+    }
+
+    fn take_stale(&self) -> bool {
+        self.sender.take_stale()
+    }
+
+    fn account(&self) -> Option<Account> {
+        self.client.account.clone()
+    }
+}
+
+// Agent for in-process seats, event is sent as is without serialization
+pub struct ChannelAgent {
+    sender: mpsc::UnboundedSender<ServerResponse>,
+    actions: Option<mpsc::UnboundedReceiver<UserRequest>>,
+}
+
+impl ChannelAgent {
+    // Owner of the seat may send actions through the other end of action channel
+    pub fn new(sender: mpsc::UnboundedSender<ServerResponse>, actions: Option<mpsc::UnboundedReceiver<UserRequest>>) -> Self {
+        Self {
+            sender,
+            actions,
+        }
+    }
+}
+
+impl PlayerAgent for ChannelAgent {
+    fn send_event(&self, res: &ServerResponse) {
+        if self.sender.send(res.clone()).is_err() {
+            eprintln!("Failed to send event to agent");
+        }
+    }
+
+    fn take_actions(&mut self) -> Option<mpsc::UnboundedReceiver<UserRequest>> {
+        self.actions.take()
+    }
+}
+
+// Scripted seat which replays recorded actions in order.
+// Next action is played whenever seat is asked to act,
+// that is a new betting state or opponent's raise.
+// Events are passed on to given sender so that replay can be observed.
+pub struct ReplayAgent {
+    events: mpsc::UnboundedSender<ServerResponse>,
+    script: Mutex<VecDeque<(PlayerAction, Option<u32>)>>,
+    state_id: Mutex<String>,
+    action_tx: mpsc::UnboundedSender<UserRequest>,
+    actions: Option<mpsc::UnboundedReceiver<UserRequest>>,
+}
+
+impl ReplayAgent {
+    pub fn new(script: Vec<(PlayerAction, Option<u32>)>, events: mpsc::UnboundedSender<ServerResponse>) -> Self {
+        let (action_tx, actions) = mpsc::unbounded_channel();
+        Self {
+            events,
+            script: Mutex::new(script.into()),
+            state_id: Mutex::new(String::new()),
+            action_tx,
+            actions: Some(actions),
+        }
+    }
+
+    fn play_next(&self) {
+        let next = self.script.lock().unwrap().pop_front();
+        if let Some((action, value)) = next {
+            let req = UserRequest {
+                state_id: self.state_id.lock().unwrap().clone(),
+                action,
+                value,
+                text: None,
+                emote: None,
+            };
+            if self.action_tx.send(req).is_err() {
+                eprintln!("Failed to send replayed action");
+            }
+        }
+    }
+}
+
+impl PlayerAgent for ReplayAgent {
+    fn send_event(&self, res: &ServerResponse) {
+        match &res.value {
+            ResponseValue::State((state, state_id)) => {
+                *self.state_id.lock().unwrap() = state_id.clone();
+                if let GameState::Flop | GameState::Turn | GameState::River = state {
+                    self.play_next();
+                }
+            }
+            ResponseValue::Raise(_) => self.play_next(),
+            _ => {}
+        }
+
+        if self.events.send(res.clone()).is_err() {
+            eprintln!("Failed to send event to agent");
+        }
+    }
+
+    fn take_actions(&mut self) -> Option<mpsc::UnboundedReceiver<UserRequest>> {
+        self.actions.take()
+    }
+}
+
+// Play every action that seats have produced so far, without runtime.
+// Scripted games and tests are run synchronously with this.
+pub fn play_seats(game: &mut Game, seats: &mut [(String, mpsc::UnboundedReceiver<UserRequest>)]) {
+    loop {
+        let mut played = false;
+        for (uid, actions) in seats.iter_mut() {
+            while let Ok(req) = actions.try_recv() {
+                let pending = game.receive_player_action(uid, req);
+                game.pending_next_state(pending);
+                played = true;
+            }
+        }
+        if !played {
+            break;
+        }
+    }
+}
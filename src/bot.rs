@@ -0,0 +1,257 @@
+use std::cmp::Ordering;
+use serde::{ Deserialize , Serialize};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use futures::StreamExt;
+use rand::prelude::*;
+use tokio::time::Instant;
+
+use crate::agent::ChannelAgent;
+use crate::handlers::drive_agent;
+use crate::room::RoomHandle;
+use crate::models::{Card, CardPool, CardCombination, CombinationBuilder, Game, GameState, PlayerAction, ServerResponse, ResponseType, ResponseValue, UserRequest};
+
+// Seconds that bot waits before sending action so that it feels like a player
+const BOT_THINK_TIME : u64 = 1;
+// Amount of random boards simulated for equity estimation
+const EQUITY_SAMPLES : usize = 300;
+const COMMUNITY_SIZE : usize = 5;
+const BOT_RAISE : u32 = 1;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BotLevel {
+    // Picks any action randomly
+    Random,
+    // Decides with current combination only
+    Basic,
+    // Decides with estimated winning chance against random hand
+    Equity,
+}
+
+// Bot is seated as a participant with channel agent.
+// Every event sent to bot is read from receiver
+// and bot sends its action through agent's action channel
+// which room plays same as websocket user's.
+pub fn join_bot(game: &mut Game, room: &RoomHandle, level: BotLevel) {
+    if game.participant.is_some() {
+        eprintln!("Tried to join bot to full room");
+        return;
+    }
+
+    let (bot_tx, bot_rx) = mpsc::unbounded_channel();
+    let (action_tx, action_rx) = mpsc::unbounded_channel();
+    let bot_id = Uuid::new_v4().to_simple().to_string();
+
+    let mut agent = ChannelAgent::new(bot_tx, Some(action_rx));
+    drive_agent(room, &bot_id, &mut agent);
+    game.join_game(bot_id, Box::new(agent));
+    game.init_game();
+
+    let bot = Bot::new(level, action_tx);
+    tokio::task::spawn(bot.run(bot_rx));
+}
+
+// Action that bot is going to take after thinking time
+#[derive(Clone, Copy)]
+enum Decision {
+    Open,
+    AgainstRaise(u32),
+    Rematch,
+}
+
+pub struct Bot {
+    level: BotLevel,
+    actions: mpsc::UnboundedSender<UserRequest>,
+    state_id: String,
+    hand: Vec<Card>,
+    community: Vec<Card>,
+    // Blind of the room which is told by env
+    stakes: u32,
+    total_bet: u32,
+    pending: Option<(Instant, Decision)>,
+}
+
+impl Bot {
+    pub fn new(level: BotLevel, actions: mpsc::UnboundedSender<UserRequest>) -> Self {
+        Self {
+            level,
+            actions,
+            state_id: String::new(),
+            hand: vec![],
+            community: vec![],
+            stakes: 0,
+            total_bet: 0,
+            pending: None,
+        }
+    }
+
+    pub async fn run(mut self, mut receiver: mpsc::UnboundedReceiver<ServerResponse>) {
+        loop {
+            // Decision is made after thinking time so that
+            // cards sent right after state change are also received.
+            let next = if let Some((deadline, decision)) = self.pending {
+                if let Ok(next) = tokio::time::timeout_at(deadline, receiver.next()).await {
+                    next
+                } else {
+                    self.pending = None;
+                    self.decide(decision);
+                    continue;
+                }
+            } else {
+                receiver.next().await
+            };
+
+            // Receiver is closed when room is removed
+            let res = if let Some(res) = next {
+                res
+            } else {
+                break;
+            };
+
+            self.receive_response(res);
+        }
+    }
+
+    fn receive_response(&mut self, res: ServerResponse) {
+        match res.value {
+            ResponseValue::State((state, state_id)) => {
+                self.state_id = state_id;
+                match state {
+                    GameState::Flop | GameState::Turn | GameState::River => {
+                        self.think(Decision::Open);
+                    }
+                    GameState::Lobby => {
+                        self.think(Decision::Rematch);
+                    }
+                    _ => {
+                        self.pending = None;
+                    }
+                }
+            }
+            ResponseValue::Card(cards) => {
+                // Hand is only sent at the start of round
+                // and community is sent as whole at first.
+                if let ResponseType::Hand = res.response_type {
+                    self.hand = cards;
+                    // Both players have put blind
+                    self.total_bet = self.stakes * 2;
+                } else if cards.len() >= 3 {
+                    self.community = cards;
+                } else {
+                    self.community.extend(cards);
+                }
+            }
+            ResponseValue::Env(env) => {
+                self.stakes = env.stakes;
+            }
+            ResponseValue::BetResult(result) => {
+                self.total_bet = result.total_bet;
+            }
+            ResponseValue::Raise(amount) => {
+                self.think(Decision::AgainstRaise(amount));
+            }
+            _ => {}
+        }
+    }
+
+    fn think(&mut self, decision: Decision) {
+        let deadline = Instant::now() + std::time::Duration::from_secs(BOT_THINK_TIME);
+        self.pending.replace((deadline, decision));
+    }
+
+    fn decide(&self, decision: Decision) {
+        let (action, value) = match decision {
+            Decision::Open => self.decide_open(),
+            Decision::AgainstRaise(amount) => self.decide_against_raise(amount),
+            Decision::Rematch => (PlayerAction::Rematch, None),
+        };
+        self.send_action(action, value);
+    }
+
+    fn send_action(&self, action: PlayerAction, value: Option<u32>) {
+        let req = UserRequest {
+            state_id: self.state_id.clone(),
+            action,
+            value,
+            text: None,
+            emote: None,
+        };
+
+        if self.actions.send(req).is_err() {
+            eprintln!("Bot has left the room");
+        }
+    }
+
+    fn decide_open(&self) -> (PlayerAction, Option<u32>) {
+        let raise = match self.level {
+            BotLevel::Random => rand::thread_rng().gen_bool(0.5),
+            BotLevel::Basic => self.current_combination() as u8 >= CardCombination::TwoPair as u8,
+            BotLevel::Equity => self.equity() >= 0.65,
+        };
+
+        if raise {
+            (PlayerAction::Raise, Some(BOT_RAISE))
+        } else {
+            (PlayerAction::Check, Some(0))
+        }
+    }
+
+    fn decide_against_raise(&self, amount: u32) -> (PlayerAction, Option<u32>) {
+        let call = match self.level {
+            BotLevel::Random => rand::thread_rng().gen_bool(0.5),
+            BotLevel::Basic => self.current_combination() as u8 >= CardCombination::Pair as u8,
+            BotLevel::Equity => {
+                // Call only if winning chance is better than pot odds
+                let pot_odds = amount as f64 / (self.total_bet as f64 + 2.0 * amount as f64);
+                self.equity() >= pot_odds
+            }
+        };
+
+        if call {
+            (PlayerAction::Call, Some(amount))
+        } else {
+            (PlayerAction::Fold, None)
+        }
+    }
+
+    fn current_combination(&self) -> CardCombination {
+        let cards = self.community.iter().chain(self.hand.iter()).cloned().collect::<Vec<Card>>();
+        CombinationBuilder::get_highest_combination(cards).0
+    }
+
+    fn equity(&self) -> f64 {
+        estimate_equity(&self.hand, &self.community, EQUITY_SAMPLES)
+    }
+}
+
+// Estimate winning chance of given hand against random opponent hand
+// by simulating rest of community cards.
+// Draw counts as half of win.
+pub fn estimate_equity(hand: &[Card], community: &[Card], samples: usize) -> f64 {
+    let mut deck = CardPool::new().cards;
+    deck.retain(|card| !hand.contains(card) && !community.contains(card));
+
+    let missing = COMMUNITY_SIZE.saturating_sub(community.len());
+    let mut rng = rand::thread_rng();
+    let mut score = 0.0;
+
+    for _ in 0..samples {
+        let drawn = deck.choose_multiple(&mut rng, missing + 2).cloned().collect::<Vec<Card>>();
+        let (opp_hand, board) = drawn.split_at(2);
+
+        let user_cards = community.iter().chain(board.iter()).chain(hand.iter()).cloned().collect::<Vec<Card>>();
+        let opp_cards = community.iter().chain(board.iter()).chain(opp_hand.iter()).cloned().collect::<Vec<Card>>();
+
+        let (user_comb, user_meta) = CombinationBuilder::get_highest_combination(user_cards);
+        let (opp_comb, opp_meta) = CombinationBuilder::get_highest_combination(opp_cards);
+
+        match CombinationBuilder::compare_combination(user_comb, &user_meta, opp_comb, &opp_meta) {
+            Ordering::Greater => score += 1.0,
+            Ordering::Equal => score += 0.5,
+            Ordering::Less => {}
+        }
+    }
+
+    score / samples as f64
+}
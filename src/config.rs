@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use toml::Value;
+use warp::http::Uri;
+
+use crate::accounts::random_secret;
+use crate::heartbeat::HeartbeatConfig;
+use crate::models::{GameMode, Rules, Timers};
+use crate::outbox::{OutboxConfig, SlowClientPolicy};
+use crate::room::RoomConfig;
+
+const DEFAULT_PORT : u16 = 3030;
+// SQLite database of players and match history
+const DATABASE_PATH : &str = "card_server.db";
+// Seconds that token issued on login stays valid
+const TOKEN_TTL : u64 = 7 * 24 * 60 * 60;
+// Every key of config file can be overridden with CARD_<SECTION>_<KEY>
+const ENV_PREFIX : &str = "CARD";
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "card_server")]
+pub struct Cli {
+    #[structopt(long, parse(from_os_str), help = "Path of server.toml")]
+    pub config: Option<PathBuf>,
+    #[structopt(long, help = "Address to listen on, overrides config file")]
+    pub bind: Option<IpAddr>,
+    #[structopt(long, help = "Port to listen on, overrides config file")]
+    pub port: Option<u16>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    #[structopt(about = "Export protocol schema instead of running server")]
+    Schema {
+        #[structopt(parse(from_os_str), default_value = "schema")]
+        dir: PathBuf,
+    },
+}
+
+// Missing sections and keys fall back to defaults
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ListenConfig,
+    pub rules: RulesConfig,
+    pub timers: Timers,
+    pub rooms: RoomLimits,
+    pub heartbeat: HeartbeatSection,
+    pub outbox: OutboxSection,
+    pub tls: TlsSection,
+    pub accounts: AccountsSection,
+    pub storage: StorageSection,
+    pub metrics: MetricsSection,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    // Any origin is allowed when empty
+    pub cors_origins: Vec<String>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            cors_origins: vec![],
+        }
+    }
+}
+
+// Rules of rooms whose creator doesn't give them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    pub mode: GameMode,
+    pub stakes: u32,
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        let rules = Rules::default();
+        Self {
+            mode: rules.mode,
+            stakes: rules.stakes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomLimits {
+    pub max_rooms: usize,
+}
+
+impl Default for RoomLimits {
+    fn default() -> Self {
+        Self {
+            max_rooms: RoomConfig::default().max_rooms,
+        }
+    }
+}
+
+// Seconds, clamped the same way as before
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatSection {
+    pub interval: u64,
+    pub timeout: u64,
+}
+
+impl Default for HeartbeatSection {
+    fn default() -> Self {
+        let heartbeat = HeartbeatConfig::default();
+        Self {
+            interval: heartbeat.interval.as_secs(),
+            timeout: heartbeat.timeout.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxSection {
+    pub capacity: usize,
+    pub policy: SlowClientPolicy,
+}
+
+impl Default for OutboxSection {
+    fn default() -> Self {
+        let outbox = OutboxConfig::default();
+        Self {
+            capacity: outbox.capacity,
+            policy: outbox.policy,
+        }
+    }
+}
+
+// Server speaks https and wss when both paths are given
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    // Pem certificate chain
+    pub cert: String,
+    // Pem PKCS#8 or RSA private key
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsSection {
+    // Key that signs auth tokens, random for each run when empty
+    pub secret: String,
+    // Seconds
+    pub token_ttl: u64,
+}
+
+impl Default for AccountsSection {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            token_ttl: TOKEN_TTL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSection {
+    // ":memory:" keeps players and history only until server stops
+    pub path: String,
+}
+
+impl Default for StorageSection {
+    fn default() -> Self {
+        Self {
+            path: DATABASE_PATH.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    // Serve GET /metrics, which anyone who reaches the server can read
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // Print a line for every http request
+    pub access: bool,
+}
+
+impl ServerConfig {
+    // Defaults are overridden by config file, then by environment
+    // and at last by command line.
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        let mut config = match cli.config.as_ref() {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config = config.with_env(&std::env::vars().collect())?;
+        if let Some(bind) = cli.bind {
+            config.server.bind = bind;
+        }
+        if let Some(port) = cli.port {
+            config.server.port = port;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Couldn't read config {} : {}", path.display(), err))?;
+        Self::parse(&text)
+            .map_err(|err| format!("Invalid config {} : {}", path.display(), err))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    // Config is turned into a toml table so that every key
+    // gets its variable without listing them by hand.
+    pub fn with_env(&self, vars: &HashMap<String, String>) -> Result<Self, String> {
+        let mut value = Value::try_from(self).map_err(|err| err.to_string())?;
+        if let Some(sections) = value.as_table_mut() {
+            for (section, table) in sections.iter_mut() {
+                let table = match table.as_table_mut() {
+                    Some(table) => table,
+                    None => continue,
+                };
+                for (key, field) in table.iter_mut() {
+                    let name = format!("{}_{}_{}", ENV_PREFIX, section, key).to_uppercase();
+                    if let Some(raw) = vars.get(&name) {
+                        *field = env_value(field, raw)
+                            .ok_or_else(|| format!("Invalid value of {} : {}", name, raw))?;
+                    }
+                }
+            }
+        }
+        value.try_into().map_err(|err: toml::de::Error| err.to_string())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.default_rules()?;
+        if self.timers.bet == 0 {
+            return Err("Bet time should be at least a second".to_string());
+        }
+        if self.rooms.max_rooms == 0 {
+            return Err("Max rooms should be at least 1".to_string());
+        }
+        if self.accounts.token_ttl == 0 {
+            return Err("Token ttl should be at least a second".to_string());
+        }
+        if self.storage.path.is_empty() {
+            return Err("Storage path should not be empty".to_string());
+        }
+        if self.tls.cert.is_empty() != self.tls.key.is_empty() {
+            return Err("TLS needs both certificate and key".to_string());
+        }
+        for origin in self.server.cors_origins.iter() {
+            if !is_origin(origin) {
+                return Err(format!("Invalid cors origin : {}", origin));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind, self.server.port)
+    }
+
+    pub fn default_rules(&self) -> Result<Rules, String> {
+        Rules::with_options(Rules::default(), Some(self.rules.mode), Some(self.rules.stakes))
+    }
+
+    pub fn room(&self) -> RoomConfig {
+        RoomConfig {
+            max_rooms: self.rooms.max_rooms,
+            rules: self.default_rules().unwrap_or_default(),
+            timers: self.timers,
+        }
+    }
+
+    // Certificate and key paths when TLS is enabled
+    pub fn tls(&self) -> Option<(PathBuf, PathBuf)> {
+        if self.tls.cert.is_empty() {
+            None
+        } else {
+            Some((PathBuf::from(&self.tls.cert), PathBuf::from(&self.tls.key)))
+        }
+    }
+
+    // Tokens signed with random secret are invalid after restart
+    pub fn secret(&self) -> Vec<u8> {
+        if self.accounts.secret.is_empty() {
+            eprintln!("No accounts secret is configured, auth tokens will be invalid after restart");
+            random_secret()
+        } else {
+            self.accounts.secret.as_bytes().to_vec()
+        }
+    }
+
+    pub fn heartbeat(&self) -> HeartbeatConfig {
+        HeartbeatConfig::new(self.heartbeat.interval, self.heartbeat.timeout)
+    }
+
+    pub fn outbox(&self) -> OutboxConfig {
+        OutboxConfig::new(self.outbox.capacity, self.outbox.policy)
+    }
+
+    // Browser clients post json to account routes and read the rest
+    pub fn cors(&self) -> warp::filters::cors::Builder {
+        let cors = warp::cors()
+            .allow_methods(vec!["GET", "POST"])
+            .allow_header("content-type");
+        if self.server.cors_origins.is_empty() {
+            cors
+        } else {
+            cors.allow_origins(self.server.cors_origins.iter().map(String::as_str))
+        }
+    }
+}
+
+// Value of variable is read as the type that key already has.
+// Lists are separated with commas.
+fn env_value(current: &Value, raw: &str) -> Option<Value> {
+    match current {
+        Value::Integer(_) => raw.trim().parse().ok().map(Value::Integer),
+        Value::Boolean(_) => raw.trim().parse().ok().map(Value::Boolean),
+        Value::Array(_) => Some(Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect()
+        )),
+        _ => Some(Value::String(raw.trim().to_string())),
+    }
+}
+
+// Warp panics on origin that isn't scheme and host
+fn is_origin(origin: &str) -> bool {
+    match origin.parse::<Uri>() {
+        Ok(uri) => uri.scheme().is_some()
+            && uri.authority().is_some()
+            && uri.path_and_query().map_or(true, |path| path.as_str() == "/")
+            && !origin.ends_with('/'),
+        Err(_) => false,
+    }
+}
+
+// Logs method, path, status and time of requests when enabled
+pub fn access_log(enabled: bool) -> warp::log::Log<impl Fn(warp::log::Info) + Copy> {
+    warp::log::custom(move |info| {
+        if enabled {
+            eprintln!("{} {} {} {:?}", info.method(), info.path(), info.status().as_u16(), info.elapsed());
+        }
+    })
+}
@@ -1,70 +1,193 @@
 use std::convert::Infallible;
-use std::sync::{ Arc , RwLock};
+use std::sync::{ Arc , PoisonError, RwLock};
 use std::collections::HashMap;
 use tokio::sync::mpsc;
 use uuid::Uuid;
 use warp::{Filter, Reply};
-use futures::{FutureExt, StreamExt, SinkExt};
+use futures::{SinkExt, StreamExt};
 use warp::ws::{Message, WebSocket};
 
-use crate::models::{Connection, UserRequest, PlayerAction, ServerResponse, ResponseType, ResponseValue, InternalRequest, IntReqType, IntReqValue};
+use crate::agent::{PlayerAgent, WebSocketAgent};
+use crate::heartbeat::{Heartbeat, HeartbeatConfig};
+use crate::outbox::{Outbox, Outboxes};
+use crate::protocol::{ClientHello, ClientInfo, ServerHello, WireFormat};
+use crate::room::{RoomEntry, RoomHandle, RoomMessage, Rooms};
+use crate::bot;
+use crate::models::{Connection, CreateOption, GameMode, JoinOption, RoomFilter, RoomInfo, Rules, LobbyEvent, User, UserRequest, WatchOption, ServerResponse, ResponseType, ResponseValue};
+
+const NO_ROOM : &str = "There is no such room with given id.";
+pub const ROOMS_FULL : &str = "Server can't open more rooms right now.";
+
+// Senders of clients who are watching lobby
+pub type Lobby = Arc<RwLock<HashMap<String, (Outbox, ClientInfo)>>>;
+
+// Rooms is given as clone object so that it is alright to just move rooms to nested functions
+// Incompatible clients are rejected right after upgrade with an error message
+pub async fn create_handler(ws: warp::ws::Ws, option: CreateOption, hello: ClientHello, rooms: Rooms, lobby: Lobby, heartbeat: HeartbeatConfig, outboxes: Outboxes) -> Result<impl Reply, Infallible> {
+    let checked = hello.client_info().and_then(|client| {
+        if rooms.is_full() {
+            return Err(ROOMS_FULL.to_string());
+        }
+        Ok((option.rules(&client, rooms.config().rules)?, client))
+    });
 
-pub type Connections = Arc<RwLock<HashMap<String, Connection>>>;
+    Ok( ws.on_upgrade(move |ws| async move {
+        match checked {
+            Ok((rules, client)) => create(ws, option, rules, client, rooms, lobby, heartbeat, outboxes).await,
+            Err(msg) => reject(ws, hello.format(), msg).await,
+        }
+    }))
+}
 
-// This conn is given as clone object so that it is alright to just move conn to nested functions
-pub async fn create_handler(ws: warp::ws::Ws, conn: Connections) -> Result<impl Reply, Infallible> {
-    Ok( ws.on_upgrade(move |ws| create(ws, conn) ))
+#[allow(clippy::too_many_arguments)]
+pub async fn join_handler(ws: warp::ws::Ws, room_key: String, option: JoinOption, hello: ClientHello, rooms: Rooms, lobby: Lobby, heartbeat: HeartbeatConfig, outboxes: Outboxes) -> Result<impl Reply, Infallible> {
+    let checked = hello.client_info().and_then(|client| {
+        let entry = resolve_room(&room_key, option.password.as_deref(), &rooms)?;
+        // Series room would leave the client in lobby state it doesn't know
+        if entry.info.rules.mode == GameMode::Series && !client.supports("series") {
+            return Err("Client doesn't support series room".to_string());
+        }
+        Ok((entry.handle, client))
+    });
+
+    Ok( ws.on_upgrade(move |ws| async move {
+        match checked {
+            Ok((room, client)) => join(ws, room, client, lobby, heartbeat, outboxes).await,
+            Err(msg) => reject(ws, hello.format(), msg).await,
+        }
+    }))
 }
 
-pub async fn join_handler(ws: warp::ws::Ws, room_id: String,conn: Connections) -> Result<impl Reply, Infallible> {
-    Ok( ws.on_upgrade(move |ws| join(ws, room_id, conn) ))
+// Room can be entered either with room id or invite code
+// and password is checked before entering the room.
+pub fn resolve_room(room_key: &str, password: Option<&str>, rooms: &Rooms) -> Result<RoomEntry, String> {
+    if let Some(entry) = rooms.find(room_key) {
+        if entry.access.check_password(password) {
+            Ok(entry)
+        } else {
+            Err("Wrong password for the room".to_string())
+        }
+    } else {
+        Err(NO_ROOM.to_string())
+    }
 }
 
-pub async fn create(ws: WebSocket, conn: Connections) {
-    let (user_tx, mut user_rx) = ws.split();
-    let (server_tx, server_rx) = mpsc::unbounded_channel();
-    let (internal_tx, mut internal_rx) = mpsc::unbounded_channel();
+// Reply to client hello with server version and features.
+// Legacy client doesn't know hello thus nothing is sent.
+pub fn send_server_hello(sender: &Outbox, client: &ClientInfo) {
+    client.send(sender, 
+        &ServerResponse::new(
+            ResponseType::Hello, 
+            ResponseValue::Hello(ServerHello::new())
+        ));
+}
 
-    // Create user id and insert into connetion hashmap
-    let user_id = Uuid::new_v4().to_simple().to_string();
-    let room_id = Uuid::new_v4().to_simple().to_string();
+// Send error in client's wire format and close the socket
+pub async fn reject(ws: WebSocket, format: WireFormat, msg: String) {
+    let (mut user_tx, _) = ws.split();
+    let msg = format.encode(
+        &ServerResponse::new(
+            ResponseType::Error, 
+            ResponseValue::Message(msg)
+        ));
+    if let Err(err) = user_tx.send(msg).await {
+        eprintln!("websocket error: {:?}", err);
+    }
+    let _ = user_tx.close().await;
+}
 
-    let msg = serde_json::to_string(&ServerResponse{
-        response_type: ResponseType::RoomId, 
-        value: ResponseValue::Message(format!("{}", room_id).to_string())}
-    ).expect("Failed to create json object");
+pub async fn lobby_handler(ws: warp::ws::Ws, hello: ClientHello, rooms: Rooms, lobby: Lobby, heartbeat: HeartbeatConfig, outboxes: Outboxes) -> Result<impl Reply, Infallible> {
+    Ok( ws.on_upgrade(move |ws| async move {
+        match hello.client_info() {
+            Ok(client) => watch_lobby(ws, client, rooms, lobby, heartbeat, outboxes).await,
+            Err(msg) => reject(ws, hello.format(), msg).await,
+        }
+    }))
+}
 
-    server_tx.send(Ok(Message::text(msg))).expect("Failed to send message");
+pub async fn rooms_handler(filter: RoomFilter, rooms: Rooms) -> Result<impl Reply, Infallible> {
+    let rooms = listed_rooms(&filter, &rooms);
+    Ok( warp::reply::json(&rooms) )
+}
 
-    conn.write().unwrap().insert(room_id.clone(), Connection::new(user_id.clone(), room_id.clone(), server_tx, internal_tx));
+pub async fn metrics_handler(outboxes: Outboxes) -> Result<impl Reply, Infallible> {
+    Ok( outboxes.metrics() )
+}
 
-    tokio::task::spawn( server_rx.forward(user_tx).map(|result| {
-        if let Err(e) = result {
-            eprintln!("websocket error: {:?}", e);
+// Public rooms which match the filter
+pub fn listed_rooms(filter: &RoomFilter, rooms: &Rooms) -> Vec<RoomInfo> {
+    rooms.listed()
+        .into_iter()
+        .filter(|info| filter.matches(&info.rules))
+        .collect()
+}
+
+pub async fn resume_handler(ws: warp::ws::Ws, token: String, hello: ClientHello, rooms: Rooms, lobby: Lobby, heartbeat: HeartbeatConfig, outboxes: Outboxes) -> Result<impl Reply, Infallible> {
+    Ok( ws.on_upgrade(move |ws| async move {
+        match hello.client_info() {
+            Ok(client) => resume(ws, token, client, rooms, lobby, heartbeat, outboxes).await,
+            Err(msg) => reject(ws, hello.format(), msg).await,
         }
-    }));
-    
-    // Create new task so that internal channel and
-    // user channel are asynchronously recived from server.
-    let room_id_clone = room_id.clone();
-    let conn_clone = conn.clone();
-    tokio::task::spawn(
-        async move{
-            while let Some(result) = internal_rx.next().await {
-                //println!("Internal message");
-                let msg = match result {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        eprintln!("websocket error {}", e);
-                        break;
-                    }
-                };
-                internal_request_handler(&room_id_clone, msg, &conn_clone).await;
-            }
+    }))
+}
+
+pub async fn watch_handler(ws: warp::ws::Ws, room_key: String, option: WatchOption, hello: ClientHello, rooms: Rooms, heartbeat: HeartbeatConfig, outboxes: Outboxes) -> Result<impl Reply, Infallible> {
+    // Spectators enter the room the same way as players
+    let checked = hello.client_info().and_then(|client| {
+        Ok((resolve_room(&room_key, option.password.as_deref(), &rooms)?.handle, client))
+    });
+
+    Ok( ws.on_upgrade(move |ws| async move {
+        match checked {
+            Ok((room, client)) => watch(ws, room, option.delay(), client, heartbeat, outboxes).await,
+            Err(msg) => reject(ws, hello.format(), msg).await,
         }
-    );
+    }))
+}
 
-    while let Some(result) = user_rx.next().await {
+#[allow(clippy::too_many_arguments)]
+pub async fn create(ws: WebSocket, option: CreateOption, rules: Rules, client: ClientInfo, rooms: Rooms, lobby: Lobby, heartbeat: HeartbeatConfig, outboxes: Outboxes) {
+    let (user_tx, mut user_rx) = ws.split();
+    let server_tx = outboxes.open(user_tx, client.supports("resync"));
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    send_server_hello(&server_tx, &client);
+    let mut heartbeat = Heartbeat::new(server_tx.clone(), heartbeat);
+
+    // Create user id and open a room
+    let user_id = Uuid::new_v4().to_simple().to_string();
+    let room_id = Uuid::new_v4().to_simple().to_string();
+
+    let agent = WebSocketAgent::new(server_tx.clone(), client.clone());
+    agent.send_event(
+        &ServerResponse::new(
+            ResponseType::RoomId, 
+            ResponseValue::Message(room_id.clone())
+        ));
+
+    let mut connection = Connection::new(user_id.clone(), room_id.clone(), Box::new(agent), commands_tx, rules, rooms.config().timers);
+    connection.access = option.access();
+    let room = rooms.open(connection, commands_rx, &lobby);
+
+    let seated = room.clone();
+    let bot_level = option.bot;
+    let room_lobby = lobby.clone();
+    room.call(move |connection| {
+        connection.game.creator.send_message(&ServerResponse::new(
+            ResponseType::InviteCode,
+            ResponseValue::Message(connection.invite_code.clone())
+        ));
+        send_token(&connection.game.creator);
+
+        // Bot takes participant seat right away
+        if let Some(level) = bot_level {
+            bot::join_bot(&mut connection.game, &seated, level);
+            publish_lobby_event(&room_lobby, connection, LobbyEvent::PlayerJoined(connection.info()));
+            publish_lobby_event(&room_lobby, connection, LobbyEvent::GameStarted(connection.info()));
+        }
+    }).await;
+
+
+    while let Some(result) = heartbeat.next(&mut user_rx).await {
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
@@ -72,69 +195,61 @@ pub async fn create(ws: WebSocket, conn: Connections) {
                 break;
             }
         };
-        user_request_handler(&room_id, &user_id, msg, &conn).await;
+        user_request_handler(&room, &user_id, 0, msg, &client);
     }
 
-    user_disconnected_handler(&room_id, &conn).await;
+    user_disconnected_handler(&room, &user_id, 0);
 }
 
-pub async fn join(ws: WebSocket, room_id: String, conn: Connections) {
+pub async fn join(ws: WebSocket, room: RoomHandle, client: ClientInfo, lobby: Lobby, heartbeat: HeartbeatConfig, outboxes: Outboxes) {
     let (user_tx, mut user_rx) = ws.split();
-    let (server_tx, server_rx) = mpsc::unbounded_channel();
+    let server_tx = outboxes.open(user_tx, client.supports("resync"));
 
-    // Create user id and insert into connetion hashmap
+    // Create user id which is seated in the room
     let user_id = Uuid::new_v4().to_simple().to_string();
 
-    // SPawn a thread for forwarding stream from server reciever to user transmitter.
-    tokio::task::spawn( server_rx.forward(user_tx).map(|result| {
-        if let Err(e) = result {
-            eprintln!("websocket error: {:?}", e);
-        }
-    }));
-
-    // If room exists
-    if let Some(connection) = conn.write().unwrap().get_mut(&room_id) {
+    send_server_hello(&server_tx, &client);
+    let mut heartbeat = Heartbeat::new(server_tx.clone(), heartbeat);
+    let agent = WebSocketAgent::new(server_tx.clone(), client.clone());
 
-        if let Some(_) = connection.game.participant {
+    let participant_id = user_id.clone();
+    let joined = room.call(move |connection| {
+        if connection.game.participant.is_some() {
             // Already room is full
-            let msg = serde_json::to_string(&ServerResponse{
-                response_type: ResponseType::Error, 
-                value: ResponseValue::Message(format!("Currently room is full").to_string())})
-                .expect("Failed to create json object");
-
-            server_tx.send(Ok(Message::text(msg))).expect("Failed to send message");
-            return;
+            return Err("Currently room is full".to_string());
+        }
+        // Account can't play against itself
+        if agent.account().is_some() && agent.account() == connection.game.creator.account {
+            return Err("You are already seated in this room".to_string());
         }
 
-        let msg = serde_json::to_string(&ServerResponse{
-            response_type: ResponseType::Message, 
-            value: ResponseValue::Message(format!("Successfully joined a room : {}", room_id).to_string())})
-            .expect("Failed to create json object");
-
-        server_tx.send(Ok(Message::text(msg))).expect("Failed to send message");
+        agent.send_event(
+            &ServerResponse::new(
+                ResponseType::Message, 
+                ResponseValue::Message(format!("Successfully joined a room : {}", connection.room_id))
+            ));
         // Set connection into room
-        connection.game.join_game(user_id.clone(), server_tx);
+        connection.game.join_game(participant_id, Box::new(agent));
+        send_token(connection.game.participant.as_ref().unwrap());
         // Initialize game.
         // Which make community field and hand of each players 
         // And also sends card information to each clients.
         connection.game.init_game();
-    } else {
-        // Reject
-        let msg = serde_json::to_string(&ServerResponse{
-            response_type: ResponseType::Error, 
-            value: ResponseValue::Message("There is no such room with given id.".to_string())})
-            .expect("Failed to create json object");
 
-        server_tx.send(Ok(Message::text(msg))).expect("Failed to send message");
+        publish_lobby_event(&lobby, connection, LobbyEvent::PlayerJoined(connection.info()));
+        publish_lobby_event(&lobby, connection, LobbyEvent::GameStarted(connection.info()));
+        Ok(())
+    }).await;
+
+    // Room might have been closed after it was looked up
+    if let Err(msg) = joined.unwrap_or_else(|| Err(NO_ROOM.to_string())) {
+        send_error(&server_tx, &client, msg);
         return;
     }
 
-    // TODO :: Change this opertion from insert into modification.
-    //conn.lock().unwrap().insert(user_id.clone(), Connection::new(user_id.clone(), room_id, server_tx));
-
 
     // From user client to server receiver.
-    while let Some(result) = user_rx.next().await {
+    while let Some(result) = heartbeat.next(&mut user_rx).await {
         let msg = match result {
             Ok(msg) => msg,
             Err(e) => {
@@ -142,125 +257,221 @@ pub async fn join(ws: WebSocket, room_id: String, conn: Connections) {
                 break;
             }
         };
-        user_request_handler(&room_id, &user_id, msg, &conn).await;
+        user_request_handler(&room, &user_id, 0, msg, &client);
         //user_message(&room_id, &user_id, msg, &conn).await;
     }
 
-    user_disconnected_handler(&room_id, &conn).await;
+    user_disconnected_handler(&room, &user_id, 0);
 }
 
-pub async fn internal_request_handler(room_id: &str, msg: Message, conn: &Connections) {
-    // Skip any non-Text messages...
-    let msg = if let Ok(s) = msg.to_str() {
-        s
+pub async fn resume(ws: WebSocket, token: String, client: ClientInfo, rooms: Rooms, lobby: Lobby, heartbeat: HeartbeatConfig, outboxes: Outboxes) {
+    let (user_tx, mut user_rx) = ws.split();
+    let server_tx = outboxes.open(user_tx, client.supports("resync"));
+
+    send_server_hello(&server_tx, &client);
+    let mut heartbeat = Heartbeat::new(server_tx.clone(), heartbeat);
+
+    // Find room which has user with given token
+    let mut resumed: Option<(RoomHandle, String, u32)> = None;
+    if let Some(room) = rooms.find_token(&token) {
+        let agent = Box::new(WebSocketAgent::new(server_tx.clone(), client.clone()));
+        let seat = room.call(move |connection| {
+            let seat = connection.game.resume_user(&token, agent);
+            if seat.is_some() {
+                eprintln!("User resumed a game in room : {}", connection.room_id);
+                publish_lobby_event(&lobby, connection, LobbyEvent::PlayerJoined(connection.info()));
+            }
+            seat
+        }).await.flatten();
+        if let Some((user_id, session)) = seat {
+            resumed.replace((room, user_id, session));
+        }
+    }
+
+    let (room, user_id, session) = if let Some(resumed) = resumed {
+        resumed
     } else {
+        send_error(&server_tx, &client, "There is no game to resume with given token.".to_string());
         return;
     };
 
-    let mut req: InternalRequest = InternalRequest::dummy();
-    if let Ok(request) = serde_json::from_str(msg) {
-        req = request;
-    } else {
-        eprintln!("Failed to parse Internal Request");
-        eprintln!("{}", msg);
-       return; 
-    }
-    //eprintln!("Successfully fetched internal request of type : {:?}", req.request_type);
-
-    match req.request_type {
-        // NOTE
-        // Timeout is only nested one time.
-        // Which means timeout exception can only occur once.
-        // Such that internal_request doesn't have to wait for multiple
-        // times before completion.
-        IntReqType::TimeOut => {
-            if let IntReqValue::TimeOut(time_out) = req.value {
-                //eprintln!("Wait for {:?} seconds", time_out.duration);
-
-                // Create a new task, namely concurrent workflow
-                // so that timeout delay is done asynchronously
-                // while internal_channel can recieve another request
-                let conn_clone = conn.clone();
-                let room_id_clone = room_id.clone().to_string();
-                tokio::task::spawn(async move{
-                    tokio::time::delay_for(std::time::Duration::from_secs(time_out.duration.as_secs())).await;
-
-                    // Get conection
-                    if let Ok(mut hash) = conn_clone.write() {
-                        if let Some(connection) = hash.get_mut(&room_id_clone) {
-                            // If state has been extended ignore request and drop.
-                            //eprintln!("Timeout");
-                            connection.game.next_state(&time_out.state_id);
-                        } else {
-                            eprintln!("Skipped request because connection lost");
-                        }
-                    } else {
-                        eprintln!("Connection lost, skip timeout");
-                    }
-                });
-            } else {
-                eprintln!("Cannot find duration value from timeout request");
+    while let Some(result) = heartbeat.next(&mut user_rx).await {
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("websocket error {}", e);
+                break;
             }
+        };
+        user_request_handler(&room, &user_id, session, msg, &client);
+    }
+
+    user_disconnected_handler(&room, &user_id, session);
+}
+
+pub async fn watch_lobby(ws: WebSocket, client: ClientInfo, rooms: Rooms, lobby: Lobby, heartbeat: HeartbeatConfig, outboxes: Outboxes) {
+    let (user_tx, mut user_rx) = ws.split();
+    // Spectators and lobby have no snapshot to catch up with
+    let server_tx = outboxes.open(user_tx, false);
+    let lobby_id = Uuid::new_v4().to_simple().to_string();
+
+    send_server_hello(&server_tx, &client);
+    let mut heartbeat = Heartbeat::new(server_tx.clone(), heartbeat);
+
+    // Send current rooms first and then every change is sent as event
+    client.send(&server_tx, 
+        &ServerResponse::new(
+            ResponseType::Rooms, 
+            ResponseValue::Rooms(rooms.listed())
+        ));
+
+    lobby.write().unwrap_or_else(PoisonError::into_inner).insert(lobby_id.clone(), (server_tx, client));
+
+    // Lobby is read only, stream is only read to detect disconnection.
+    while let Some(result) = heartbeat.next(&mut user_rx).await {
+        if let Err(e) = result {
+            eprintln!("websocket error {}", e);
+            break;
         }
-        IntReqType::GameEnd => {
-            if let Ok(mut hash) = conn.write() {
-                hash.remove(room_id);
-            } else {
-                eprintln!("Connection lost");
-            }
+    }
+
+    lobby.write().unwrap_or_else(PoisonError::into_inner).remove(&lobby_id);
+}
+
+// Events of private rooms are not published
+pub fn publish_lobby_event(lobby: &Lobby, connection: &Connection, event: LobbyEvent) {
+    if !connection.is_listed() {
+        return;
+    }
+
+    let res = ServerResponse::new(
+        ResponseType::Lobby, 
+        ResponseValue::Lobby(event)
+    );
+
+    for (sender, client) in lobby.read().unwrap_or_else(PoisonError::into_inner).values() {
+        client.send(sender, &res);
+    }
+}
+
+pub fn send_token(user: &User) {
+    user.send_message(
+        &ServerResponse::new(
+            ResponseType::Token, 
+            ResponseValue::Message(user.token.clone())
+        ));
+}
+
+pub async fn watch(ws: WebSocket, room: RoomHandle, delay: u64, client: ClientInfo, heartbeat: HeartbeatConfig, outboxes: Outboxes) {
+    let (user_tx, mut user_rx) = ws.split();
+    // Spectators and lobby have no snapshot to catch up with
+    let server_tx = outboxes.open(user_tx, false);
+
+    // Spectator id is only used to remove spectator from the room
+    let spectator_id = Uuid::new_v4().to_simple().to_string();
+
+    send_server_hello(&server_tx, &client);
+    let mut heartbeat = Heartbeat::new(server_tx.clone(), heartbeat);
+
+    let id = spectator_id.clone();
+    let sender = server_tx.clone();
+    let spectator = client.clone();
+    let watching = room.call(move |connection| {
+        spectator.send(&sender, 
+            &ServerResponse::new(
+                ResponseType::Message, 
+                ResponseValue::Message(format!("Watching a room : {}", connection.room_id))
+            ));
+        // Spectator is never a participant of the game
+        connection.game.add_spectator(id, sender, spectator, delay);
+    }).await;
+
+    if watching.is_none() {
+        send_error(&server_tx, &client, NO_ROOM.to_string());
+        return;
+    }
+
+    // Spectators cannot send any actions, every message is ignored
+    // and stream is only read to detect disconnection.
+    while let Some(result) = heartbeat.next(&mut user_rx).await {
+        if let Err(e) = result {
+            eprintln!("websocket error {}", e);
+            break;
         }
-        _ => {}
     }
+
+    room.call(move |connection| connection.game.remove_spectator(&spectator_id)).await;
 }
 
-pub async fn user_request_handler(room_id: &str, user_id: &str, msg: Message, conn: &Connections) {
-    // Skip any non-Text messages...
-    let msg = if let Ok(s) = msg.to_str() {
-        s
-    } else {
+pub fn user_request_handler(room: &RoomHandle, user_id: &str, session: u32, msg: Message, client: &ClientInfo) {
+    // Skip ping, pong and close frames
+    if !msg.is_text() && !msg.is_binary() {
         return;
-    };
+    }
 
-    let mut req: UserRequest = UserRequest::dummy();
-    if let Ok(request) = serde_json::from_str(msg) {
-        req = request;
+    // Text frame is json and binary frame is client's wire format
+    let req: UserRequest = if let Some(request) = client.decode(&msg) {
+        request
     } else {
         eprintln!("Failed to parse userrequest");
-        eprintln!("{}", msg);
-       return; 
-    }
+        eprintln!("{:?}", msg);
+        return;
+    };
 
     //eprintln!("Received user request");
     //eprintln!("{:?}", req);
-    let mut hash = conn.write().unwrap();
-    if let Some(connection) = hash.get_mut(room_id) {
-        // New message from this user, send it to everyone else (except same uid)...
-        let pending = connection.game.receive_player_action(&user_id, req);
-        connection.game.pending_next_state(pending);
+    user_action_handler(room, user_id, session, req);
+}
+
+// Room plays actions that agent produces by itself, e.g. bot or replay.
+// Task ends when agent stops producing actions or room is closed.
+pub fn drive_agent(room: &RoomHandle, user_id: &str, agent: &mut dyn PlayerAgent) {
+    let mut actions = if let Some(actions) = agent.take_actions() {
+        actions
     } else {
+        return;
+    };
+
+    let room = room.clone();
+    let user_id = user_id.to_string();
+    tokio::task::spawn(async move {
+        // In-process agent is never resumed thus stays in first session
+        while let Some(req) = actions.next().await {
+            if !user_action_handler(&room, &user_id, 0, req) {
+                break;
+            }
+        }
+    });
+}
+
+// Every seat sends its action through this handler
+// whether it is websocket client or in-process agent.
+// Returns false when room has been closed.
+pub fn user_action_handler(room: &RoomHandle, user_id: &str, session: u32, req: UserRequest) -> bool {
+    let sent = room.send(RoomMessage::Action { user_id: user_id.to_string(), session, req });
+    if !sent {
         eprintln!("Connection lost");
     }
+    sent
 }
 
-pub async fn user_disconnected_handler(room_id: &str, conn: &Connections) {
-    eprintln!("User disconnected");
-
-    // Stream closed up, so remove from the user list
-    if let Some(connection) = conn.write().unwrap().remove(room_id) {
-        // If participant exists broadcast_message which technically means send message to 
-        // user who is still in connection.
-        // Message is not being sent to user who has been disconnected since connection is lost.
-        if let Some(_) = connection.game.participant {
-            let res = ServerResponse::new_json(
-                ResponseType::Error, 
-                ResponseValue::Message("Opponent player disconnected".to_string())
-            ).expect("Failed to create server response");
-            connection.game.broadcast_message(&res);
-        }
-        drop(connection);
-    }
+// Room decides whether to close or keep the seat for grace period
+pub fn user_disconnected_handler(room: &RoomHandle, user_id: &str, session: u32) {
+    room.send(RoomMessage::Disconnect { user_id: user_id.to_string(), session });
+}
+
+fn send_error(sender: &Outbox, client: &ClientInfo, msg: String) {
+    client.send(sender, 
+        &ServerResponse::new(
+            ResponseType::Error, 
+            ResponseValue::Message(msg)
+        ));
+}
+
+pub fn with_rooms(rooms: Rooms) -> impl Filter<Extract = (Rooms,), Error = Infallible> + Clone {
+    warp::any().map(move || rooms.clone())
 }
 
-pub fn with_conns(conn: Connections) -> impl Filter<Extract = (Connections,), Error = Infallible> + Clone {
-    warp::any().map(move || conn.clone())
+pub fn with_lobby(lobby: Lobby) -> impl Filter<Extract = (Lobby,), Error = Infallible> + Clone {
+    warp::any().map(move || lobby.clone())
 }
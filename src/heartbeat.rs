@@ -0,0 +1,104 @@
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::time::Instant;
+use futures::{Stream, StreamExt};
+use warp::Filter;
+use warp::ws::Message;
+
+use crate::outbox::{Outbox, Priority};
+
+// Seconds between pings sent to client
+const HEARTBEAT_INTERVAL : u64 = 10;
+// Seconds without any frame from client after which connection is regarded as dead
+const HEARTBEAT_TIMEOUT : u64 = 30;
+// Client should answer at least one of this many pings before timeout
+const MIN_PINGS_PER_TIMEOUT : u64 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(HEARTBEAT_INTERVAL),
+            timeout: Duration::from_secs(HEARTBEAT_TIMEOUT),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    // Interval of 0 would ping in a busy loop and timeout shorter than
+    // a couple of intervals closes live clients before they can answer,
+    // thus values are clamped.
+    pub fn new(interval_secs: u64, timeout_secs: u64) -> Self {
+        let interval = interval_secs.max(1);
+        let timeout = timeout_secs.max(interval * MIN_PINGS_PER_TIMEOUT);
+        if interval != interval_secs || timeout != timeout_secs {
+            eprintln!("Heartbeat is clamped to interval {}s and timeout {}s", interval, timeout);
+        }
+        Self {
+            interval: Duration::from_secs(interval),
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+}
+
+pub fn with_heartbeat(config: HeartbeatConfig) -> impl Filter<Extract = (HeartbeatConfig,), Error = Infallible> + Clone {
+    warp::any().map(move || config)
+}
+
+// Half-open socket is never closed by itself,
+// thus server pings client and gives up when client is silent for too long.
+pub struct Heartbeat {
+    sender: Outbox,
+    config: HeartbeatConfig,
+    last_seen: Instant,
+    next_ping: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(sender: Outbox, config: HeartbeatConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            sender,
+            config,
+            last_seen: now,
+            next_ping: now + config.interval,
+        }
+    }
+
+    // Next frame from client except pong.
+    // None is returned when stream has ended, heartbeat is missed
+    // or outbox is closed for a slow client
+    // so that caller goes through the same disconnect path.
+    pub async fn next<S>(&mut self, user_rx: &mut S) -> Option<Result<Message, warp::Error>>
+    where
+        S: Stream<Item = Result<Message, warp::Error>> + Unpin,
+    {
+        loop {
+            tokio::select! {
+                result = user_rx.next() => {
+                    self.last_seen = Instant::now();
+                    match result {
+                        Some(Ok(msg)) if msg.is_pong() => continue,
+                        result => return result,
+                    }
+                }
+                _ = tokio::time::delay_until(self.next_ping) => {
+                    if self.last_seen.elapsed() >= self.config.timeout {
+                        eprintln!("Missed heartbeat, closing connection");
+                        return None;
+                    }
+                    if !self.sender.send(Priority::Optional, Message::ping(vec![])) {
+                        return None;
+                    }
+                    self.next_ping = Instant::now() + self.config.interval;
+                }
+                _ = self.sender.closed() => return None,
+            }
+        }
+    }
+}
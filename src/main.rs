@@ -1,28 +1,102 @@
 mod models;
 mod handlers;
 mod routes;
+mod bot;
+mod agent;
+mod matchmaking;
+mod protocol;
+mod schema;
+mod heartbeat;
+mod room;
+mod outbox;
+mod config;
+mod tls;
+mod accounts;
+mod storage;
+#[cfg(test)]
 mod test;
 
 use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
+use structopt::StructOpt;
 use warp::Filter;
 
 use crate::handlers::*;
-use crate::routes::*;
+use crate::matchmaking::Queue;
+use crate::room::Rooms;
+use crate::outbox::Outboxes;
+use crate::config::{access_log, Cli, Command, ServerConfig};
+use crate::tls::TlsCerts;
+use crate::accounts::Accounts;
+use crate::storage::Storage;
 
 #[tokio::main]
 async fn main() {
-    // TODO ::: Refacotr Connections from type alias to proper struct 
-    let conn = Connections::new(RwLock::new(HashMap::new()));
+    let cli = Cli::from_args();
+    // `card_server schema [dir]` exports protocol schema instead of running server
+    if let Some(Command::Schema { dir }) = cli.command.as_ref() {
+        schema::export(dir).expect("Failed to export schema");
+        return;
+    }
+    let config = match ServerConfig::load(&cli) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let create_room = routes::create_room(&conn);
-    //let get_rooms_list = routes::get_room();
-    let join_room = routes::join_room(&conn);
+    let storage = Storage::open(&config.storage.path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
+    let rooms = Rooms::new(config.room(), storage.clone());
+    let lobby = Lobby::new(RwLock::new(HashMap::new()));
+    let queue = Queue::new(RwLock::new(vec![]));
+    let heartbeat = config.heartbeat();
+    let outboxes = Outboxes::new(config.outbox());
+    let accounts = Accounts::new(Arc::new(storage.clone()), &config.secret(), config.accounts.token_ttl);
+
+    let create_room = routes::create_room(&rooms, &lobby, heartbeat, &outboxes, &accounts);
+    let get_rooms = routes::get_rooms(&rooms);
+    let watch_lobby = routes::watch_lobby(&rooms, &lobby, heartbeat, &outboxes, &accounts);
+    let join_room = routes::join_room(&rooms, &lobby, heartbeat, &outboxes, &accounts);
+    let watch_room = routes::watch_room(&rooms, heartbeat, &outboxes, &accounts);
+    let resume_game = routes::resume_game(&rooms, &lobby, heartbeat, &outboxes, &accounts);
+    let quickplay = routes::quickplay(&rooms, &lobby, &queue, heartbeat, &outboxes, &accounts);
+    let get_metrics = routes::get_metrics(&outboxes, config.metrics.enabled);
+    let register = routes::register(&accounts);
+    let login = routes::login(&accounts);
+    let get_profile = routes::get_profile(&storage);
+    let get_matches = routes::get_matches(&storage);
 
     let routes = create_room
-        //.or(get_room)
+        .or(get_rooms)
+        .or(watch_lobby)
         .or(join_room)
-        .with(warp::cors());
+        .or(watch_room)
+        .or(resume_game)
+        .or(quickplay)
+        .or(get_metrics)
+        .or(register)
+        .or(login)
+        .or(get_profile)
+        .or(get_matches)
+        .with(config.cors())
+        .with(access_log(config.log.access));
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    match config.tls() {
+        Some((cert, key)) => {
+            let certs = TlsCerts::load(&cert, &key).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+            let listener = tokio::net::TcpListener::bind(config.addr()).await
+                .expect("Failed to bind address");
+            tokio::task::spawn(tls::reload_on_hangup(certs.clone()));
+            tls::serve(routes, listener, certs).await;
+        }
+        None => warp::serve(routes).run(config.addr()).await,
+    }
 }
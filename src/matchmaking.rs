@@ -0,0 +1,240 @@
+use std::convert::Infallible;
+use std::sync::{ Arc , PoisonError, RwLock};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use uuid::Uuid;
+use warp::{Filter, Reply};
+use futures::StreamExt;
+use warp::ws::WebSocket;
+
+use crate::accounts::Account;
+use crate::agent::WebSocketAgent;
+use crate::handlers::{Lobby, ROOMS_FULL, publish_lobby_event, reject, send_server_hello, send_token, user_disconnected_handler, user_request_handler};
+use crate::room::{RoomHandle, Rooms};
+use crate::heartbeat::{Heartbeat, HeartbeatConfig};
+use crate::outbox::{Outbox, Outboxes};
+use crate::protocol::{ClientHello, ClientInfo};
+use crate::models::{Connection, LobbyEvent, QueueRequest, QueueAction, QuickplayOption, Rules, ServerResponse, ResponseType, ResponseValue};
+
+// Seconds that player waits in queue before giving up
+const QUEUE_TIME : u64 = 120;
+// Rating difference allowed right after queueing
+const RATING_RANGE : u32 = 100;
+// Allowed rating difference grows every second while waiting
+// so that players are not stuck in queue for long.
+const RATING_RANGE_PER_SEC : u32 = 5;
+
+// Players waiting for opponent in order of arrival
+pub type Queue = Arc<RwLock<Vec<QueueEntry>>>;
+
+pub struct QueueEntry {
+    id: String,
+    rules: Rules,
+    rating: Option<u32>,
+    queued_at: Instant,
+    sender: Outbox,
+    client: ClientInfo,
+    // Room id is sent when opponent is found
+    matched: oneshot::Sender<String>,
+}
+
+impl QueueEntry {
+    pub fn new(
+        id: String,
+        rules: Rules,
+        rating: Option<u32>,
+        sender: Outbox,
+        client: ClientInfo,
+        matched: oneshot::Sender<String>,
+    ) -> Self {
+        Self {
+            id,
+            rules,
+            rating,
+            queued_at: Instant::now(),
+            sender,
+            client,
+            matched,
+        }
+    }
+
+    pub fn accepts(&self, rules: &Rules, rating: Option<u32>, account: Option<&Account>) -> bool {
+        if self.rules != *rules {
+            return false;
+        }
+
+        // Account can't play against itself, e.g. from two tabs
+        if let (Some(own), Some(account)) = (&self.client.account, account) {
+            if own.id == account.id {
+                return false;
+            }
+        }
+
+        // Rating is only compared when both players have one
+        match (self.rating, rating) {
+            (Some(rating), Some(other)) => {
+                let waited = self.queued_at.elapsed().as_secs() as u32;
+                let range = RATING_RANGE + waited * RATING_RANGE_PER_SEC;
+                rating.max(other) - rating.min(other) <= range
+            }
+            _ => true,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn quickplay_handler(ws: warp::ws::Ws, option: QuickplayOption, hello: ClientHello, rooms: Rooms, lobby: Lobby, queue: Queue, heartbeat: HeartbeatConfig, outboxes: Outboxes) -> Result<impl Reply, Infallible> {
+    let checked = hello.client_info().and_then(|client| {
+        if rooms.is_full() {
+            return Err(ROOMS_FULL.to_string());
+        }
+        Ok((option.rules(&client, rooms.config().rules)?, client))
+    });
+
+    Ok( ws.on_upgrade(move |ws| async move {
+        match checked {
+            Ok((rules, client)) => quickplay(ws, option, rules, client, rooms, lobby, queue, heartbeat, outboxes).await,
+            Err(msg) => reject(ws, hello.format(), msg).await,
+        }
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn quickplay(ws: WebSocket, option: QuickplayOption, rules: Rules, client: ClientInfo, rooms: Rooms, lobby: Lobby, queue: Queue, heartbeat: HeartbeatConfig, outboxes: Outboxes) {
+    let (user_tx, mut user_rx) = ws.split();
+    let server_tx = outboxes.open(user_tx, client.supports("resync"));
+    let user_id = Uuid::new_v4().to_simple().to_string();
+
+    send_server_hello(&server_tx, &client);
+    let mut heartbeat = Heartbeat::new(server_tx.clone(), heartbeat);
+
+    // Take the first compatible player out of queue
+    let opponent = {
+        let mut entries = queue.write().unwrap_or_else(PoisonError::into_inner);
+        entries.iter()
+            .position(|entry| entry.accepts(&rules, option.rating, client.account.as_ref()))
+            .map(|index| entries.remove(index))
+    };
+
+    let room = if let Some(opponent) = opponent {
+        start_match(opponent, &user_id, WebSocketAgent::new(server_tx, client.clone()), rules, &rooms, &lobby).await
+    } else {
+        // Nobody to play with, wait in queue
+        let (matched_tx, mut matched_rx) = oneshot::channel();
+        send_queue_message(&server_tx, &client, ResponseType::Queue, "Waiting for opponent");
+        queue.write().unwrap_or_else(PoisonError::into_inner).push(QueueEntry::new(user_id.clone(), rules, option.rating, server_tx.clone(), client.clone(), matched_tx));
+
+        let mut time_out = tokio::time::delay_for(std::time::Duration::from_secs(QUEUE_TIME));
+        let matched = loop {
+            tokio::select! {
+                room_id = &mut matched_rx => match room_id {
+                    Ok(room_id) => break Some(room_id),
+                    // Entry was dropped without match
+                    Err(_) => return,
+                },
+                _ = &mut time_out => {
+                    send_queue_message(&server_tx, &client, ResponseType::Error, "No opponent found");
+                    break None;
+                }
+                result = heartbeat.next(&mut user_rx) => match result {
+                    Some(Ok(msg)) => {
+                        let cancel = client.decode::<QueueRequest>(&msg)
+                            .map_or(false, |req| matches!(req.action, QueueAction::Cancel));
+                        if cancel {
+                            send_queue_message(&server_tx, &client, ResponseType::Queue, "Left queue");
+                            break None;
+                        }
+                    }
+                    // Disconnected while waiting
+                    _ => break None,
+                }
+            }
+        };
+
+        // Room might have been closed already if opponent left right away
+        let room = matched.or_else(|| leave_queue(&queue, &user_id, &mut matched_rx))
+            .and_then(|room_id| rooms.get(&room_id));
+        if let Some(room) = room {
+            room
+        } else {
+            return;
+        }
+    };
+
+    while let Some(result) = heartbeat.next(&mut user_rx).await {
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("websocket error {}", e);
+                break;
+            }
+        };
+        user_request_handler(&room, &user_id, 0, msg, &client);
+    }
+
+    user_disconnected_handler(&room, &user_id, 0);
+}
+
+// Remove player who gave up waiting from queue.
+// Opponent might have taken the player out of queue in the meantime,
+// then game is already started and room id of the game is returned.
+pub fn leave_queue(queue: &Queue, user_id: &str, matched_rx: &mut oneshot::Receiver<String>) -> Option<String> {
+    let mut entries = queue.write().unwrap_or_else(PoisonError::into_inner);
+    if let Some(index) = entries.iter().position(|entry| entry.id == user_id) {
+        entries.remove(index);
+        return None;
+    }
+    drop(entries);
+
+    matched_rx.try_recv().ok()
+}
+
+// Create a room where waiting player is creator and new player is participant
+async fn start_match(
+    opponent: QueueEntry, 
+    user_id: &str, 
+    agent: WebSocketAgent,
+    rules: Rules,
+    rooms: &Rooms,
+    lobby: &Lobby,
+) -> RoomHandle {
+    let room_id = Uuid::new_v4().to_simple().to_string();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+
+    let mut connection = Connection::new(opponent.id.clone(), room_id.clone(), Box::new(WebSocketAgent::new(opponent.sender, opponent.client)), commands_tx, rules, rooms.config().timers);
+    connection.game.join_game(user_id.to_string(), Box::new(agent));
+
+    let res = ServerResponse::new(ResponseType::RoomId, ResponseValue::Message(room_id.clone()));
+    let game = &connection.game;
+    for user in [&game.creator, game.participant.as_ref().unwrap()].iter() {
+        user.send_message(&res);
+        send_token(user);
+    }
+    connection.game.init_game();
+
+    // Room is open to nobody else, game starts right after it is created
+    let room = rooms.open(connection, commands_rx, lobby);
+    let room_lobby = lobby.clone();
+    room.call(move |connection| {
+        publish_lobby_event(&room_lobby, connection, LobbyEvent::GameStarted(connection.info()));
+    }).await;
+
+    // Waiting player has left right before match
+    if opponent.matched.send(room_id).is_err() {
+        user_disconnected_handler(&room, &opponent.id, 0);
+    }
+
+    room
+}
+
+fn send_queue_message(sender: &Outbox, client: &ClientInfo, response_type: ResponseType, msg: &str) {
+    client.send(sender, 
+        &ServerResponse::new(
+            response_type, 
+            ResponseValue::Message(msg.to_string())
+        ));
+}
+
+pub fn with_queue(queue: Queue) -> impl Filter<Extract = (Queue,), Error = Infallible> + Clone {
+    warp::any().map(move || queue.clone())
+}
@@ -1,30 +1,60 @@
-use std::collections::HashMap;
 use std::hash::Hash;
 use std::cmp::Ordering;
 use strum_macros::Display;
 use serde::{ Deserialize , Serialize};
+use schemars::JsonSchema;
 use tokio::sync::mpsc;
-use warp::ws::Message;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 use rand::distributions::{Distribution, Uniform};
 use rand::prelude::*;
 use uuid::Uuid;
 
+use crate::agent::PlayerAgent;
+use crate::accounts::Account;
+use crate::bot::BotLevel;
+use crate::protocol::{ClientInfo, ServerHello};
+use crate::room::RoomCommand;
+use crate::outbox::{Outbox, Priority};
+use crate::storage::{HandRecord, MatchRecord};
+
 const CARD_MAX_NUMBER: usize = 13;
 const COMB_COUNT: usize = 5;
-const BET_TIME : u64 = 60;
+// Time for each action, player uses up time bank when takes longer
+const BET_TIME : u64 = 30;
+// Extra time which lasts across the whole game
+const TIME_BANK : u64 = 60;
 const SHOWDOWN_TIME: u64 = 8;
 const CARD_NUMBER : usize = 14;
 const DEFAULT_HP : u32 = 20;
+const LOBBY_TIME : u64 = 30;
+const SERIES_LENGTH : u32 = 3;
+// Default initial bet that each player puts every round
+const BLIND : u32 = 1;
+// Highest blind, game with higher blind would be over in a couple of rounds
+const MAX_STAKES : u32 = DEFAULT_HP / 4;
+// Consecutive timeouts after which player is marked as sitting out
+const SIT_OUT_TIMEOUTS : u32 = 2;
+// Seconds that disconnected player has to resume the game
+const RECONNECT_TIME : u64 = 60;
+// Longest broadcast delay in seconds that spectator can ask for
+const MAX_SPECTATOR_DELAY : u64 = 300;
+// Max characters of a chat message
+const CHAT_LENGTH : usize = 200;
+// Amount of recent chat messages kept for late joiners
+const CHAT_HISTORY : usize = 50;
+// Shown for players who are not logged in
+pub const GUEST_NAME : &str = "Guest";
+const INVITE_CODE_LENGTH : usize = 6;
+// Letters and digits that are hard to confuse with each other, no 0/O, 1/I/L
+const INVITE_CODE_CHARS : &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
 
 // TODO :: Make submodels
 
-// TODO :: Actually single Connection hashmap is really inefficient.
-// Rather make it an array of multiple hashamp. 
-// Or implement multi refernece approcach.
 pub struct Connection {
     pub room_id: String,
+    pub invite_code: String,
+    pub access: RoomAccess,
     pub game: Game,
 }
 
@@ -32,45 +62,276 @@ impl Connection {
     pub fn new(
         creator_id: String, 
         room_id: String, 
-        sender: mpsc::UnboundedSender<Result<Message, warp::Error>>,
-        internal_sender: mpsc::UnboundedSender<Result<Message, warp::Error>>
+        agent: Box<dyn PlayerAgent>,
+        commands: mpsc::UnboundedSender<RoomCommand>,
+        rules: Rules,
+        timers: Timers,
     ) -> Self {
         Self {  
             room_id,
-            game: Game::new(creator_id, sender, internal_sender),
+            invite_code: new_invite_code(),
+            access: RoomAccess::default(),
+            game: Game::new(creator_id, agent, commands, rules, timers),
+        }
+    }
+
+    pub fn is_listed(&self) -> bool {
+        self.access.is_listed()
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            room_id: self.room_id.clone(),
+            invite_code: self.invite_code.clone(),
+            rules: self.game.rules,
+            seats_taken: if self.game.participant.is_some() { 2 } else { 1 },
+            in_progress: self.game.participant.is_some(),
+        }
+    }
+}
+
+// Short code that players can type instead of room id
+pub fn new_invite_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..INVITE_CODE_LENGTH)
+        .map(|_| *INVITE_CODE_CHARS.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
+#[derive(Default, Clone)]
+pub struct RoomAccess {
+    // Private room can be joined only with room id or invite code
+    pub private: bool,
+    pub password: Option<String>,
+}
+
+impl RoomAccess {
+    // Private or password protected rooms are not shown in listing
+    pub fn is_listed(&self) -> bool {
+        !self.private && self.password.is_none()
+    }
+
+    pub fn check_password(&self, password: Option<&str>) -> bool {
+        match &self.password {
+            Some(room_password) => password == Some(room_password.as_str()),
+            None => true,
+        }
+    }
+}
+
+// Options given to create route as query
+#[derive(Deserialize)]
+pub struct CreateOption {
+    pub bot: Option<BotLevel>,
+    pub mode: Option<GameMode>,
+    pub stakes: Option<u32>,
+    pub private: Option<bool>,
+    pub password: Option<String>,
+}
+
+// Options given to join route as query
+#[derive(Deserialize)]
+pub struct JoinOption {
+    pub password: Option<String>,
+}
+
+impl CreateOption {
+    pub fn access(&self) -> RoomAccess {
+        RoomAccess {
+            private: self.private.unwrap_or(false),
+            password: self.password.clone().filter(|password| !password.is_empty()),
+        }
+    }
+
+    pub fn rules(&self, client: &ClientInfo, default: Rules) -> Result<Rules, String> {
+        Rules::with_options(default, self.mode.or_else(|| GameMode::fallback(client)), self.stakes)
+    }
+}
+
+// Options given to quickplay route as query
+#[derive(Deserialize)]
+pub struct QuickplayOption {
+    pub mode: Option<GameMode>,
+    pub stakes: Option<u32>,
+    // Players with rating are paired with similar rating
+    pub rating: Option<u32>,
+}
+
+impl QuickplayOption {
+    pub fn rules(&self, client: &ClientInfo, default: Rules) -> Result<Rules, String> {
+        Rules::with_options(default, self.mode.or_else(|| GameMode::fallback(client)), self.stakes)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, JsonSchema)]
+pub enum QueueAction {
+    Cancel,
+}
+
+// Request sent by client while waiting in quickplay queue
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct QueueRequest {
+    pub action: QueueAction,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum GameMode {
+    // Room is closed after a single game
+    Single,
+    // Best-of-N games in the same room
+    Series,
+}
+
+impl GameMode {
+    // Client without series feature can't take part in rematch,
+    // thus such client gets a single game unless mode is given.
+    pub fn fallback(client: &ClientInfo) -> Option<Self> {
+        if client.supports("series") {
+            None
+        } else {
+            Some(GameMode::Single)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct Rules {
+    pub mode: GameMode,
+    // Blind that each player puts every round
+    pub stakes: u32,
+}
+
+impl Rules {
+    // Rules with server defaults for missing options.
+    // Error is returned when stakes are out of range.
+    pub fn with_options(default: Rules, mode: Option<GameMode>, stakes: Option<u32>) -> Result<Self, String> {
+        let stakes = stakes.unwrap_or(default.stakes);
+        if stakes == 0 || stakes > MAX_STAKES {
+            return Err(format!("Stakes should be between 1 and {}", MAX_STAKES));
+        }
+        Ok(Self {
+            mode: mode.unwrap_or(default.mode),
+            stakes,
+        })
+    }
+
+    pub fn best_of(&self) -> u32 {
+        match self.mode {
+            GameMode::Single => 1,
+            GameMode::Series => SERIES_LENGTH,
+        }
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            mode: GameMode::Series,
+            stakes: BLIND,
+        }
+    }
+}
+
+// Seconds that game waits in each step
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timers {
+    pub bet: u64,
+    pub time_bank: u64,
+    pub showdown: u64,
+    pub lobby: u64,
+    pub reconnect: u64,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self {
+            bet: BET_TIME,
+            time_bank: TIME_BANK,
+            showdown: SHOWDOWN_TIME,
+            lobby: LOBBY_TIME,
+            reconnect: RECONNECT_TIME,
         }
     }
 }
 
+// Filter given to room listing as query
+#[derive(Deserialize)]
+pub struct RoomFilter {
+    pub mode: Option<GameMode>,
+    pub stakes: Option<u32>,
+}
+
+impl RoomFilter {
+    pub fn matches(&self, rules: &Rules) -> bool {
+        self.mode.map_or(true, |mode| mode == rules.mode) &&
+            self.stakes.map_or(true, |stakes| stakes == rules.stakes)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct RoomInfo {
+    pub room_id: String,
+    pub invite_code: String,
+    pub rules: Rules,
+    pub seats_taken: u8,
+    pub in_progress: bool,
+}
+
+// Room changes pushed to clients watching lobby
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub enum LobbyEvent {
+    RoomCreated(RoomInfo),
+    PlayerJoined(RoomInfo),
+    PlayerLeft(RoomInfo),
+    GameStarted(RoomInfo),
+    RoomClosed(String),
+}
+
 pub struct Game {
     pub state: GameState,
     pub state_id: Option<String>,
-    pub state_extend: bool,
-    pub internal_sender: mpsc::UnboundedSender<Result<Message, warp::Error>>,
+    // Timers and game end are handled by the room
+    pub commands: mpsc::UnboundedSender<RoomCommand>,
     pub creator: User,
     pub participant: Option<User>,
+    pub spectators: Vec<Spectator>,
     pub community: Vec<Card>,
     pub card_pool : CardPool,
+    pub rules: Rules,
+    pub timers: Timers,
+    pub series: Series,
+    pub chat: Vec<ChatMessage>,
+    // Hands of current game, stored with the game when it's over
+    pub hands: Vec<HandRecord>,
 }
 
 // Game related logics
 impl Game {
     pub fn new(
         cid: String, 
-        sender: mpsc::UnboundedSender<Result<Message, warp::Error>>,
-        internal_sender: mpsc::UnboundedSender<Result<Message, warp::Error>>,
+        agent: Box<dyn PlayerAgent>,
+        commands: mpsc::UnboundedSender<RoomCommand>,
+        rules: Rules,
+        timers: Timers,
     ) -> Self {
         // TODO :: Should poll cards several times.
         // before starting game.
         Self {  
             state: GameState::Flop,
             state_id: None,
-            state_extend: false,
-            internal_sender,
-            creator: User::new(cid, sender),
+            commands,
+            creator: User::new(cid, agent, &timers),
             participant: None,
+            spectators: vec![],
             community: vec![],
             card_pool: CardPool::new(),
+            rules,
+            timers,
+            series: Series::new(rules.best_of()),
+            chat: vec![],
+            hands: vec![],
         }
     }
 
@@ -80,60 +341,115 @@ impl Game {
             Uuid::new_v4().to_simple().to_string()
         );
 
-        let res_state = ServerResponse::new_json(
+        let res_state = ServerResponse::new(
             ResponseType::State,
             ResponseValue::State((self.state ,self.state_id.as_ref().unwrap().clone()))
-        ).expect("Failed to create resonse");
+        );
         self.creator.send_message(&res_state);
         self.participant.as_ref().unwrap().send_message(&res_state);
+        self.broadcast_spectators(&res_state);
 
         // Create Timeout
-        let req_timeout =InternalRequest::new_json(
-            IntReqType::TimeOut, 
-            IntReqValue::TimeOut(TimeOut{duration: std::time::Duration::from_secs(BET_TIME), state_id: self.state_id.as_ref().unwrap().clone()})
-        ).expect("Failed to create internal request");
-        if let Err(err) = self.internal_sender.send(Ok(Message::text(req_timeout))) {
-            eprintln!("Couldn't send internal request \n {}", err);
-        };
+        // Each state waits for different amount of time
+        // and betting states wait for each player's own deadline.
+        match self.state {
+            GameState::ShowDown => self.send_timeout(self.timers.showdown * 1000),
+            GameState::Lobby => self.send_timeout(self.timers.lobby * 1000),
+            _ => {
+                // Sitting out or disconnected player is not given a turn and checks right away
+                let now = now_millis();
+                let mut waiting = false;
+                for user in [&mut self.creator, self.participant.as_mut().unwrap()].iter_mut() {
+                    if user.is_away() {
+                        user.current_action = PlayerAction::Check;
+                    } else {
+                        user.stat.timer.start(now);
+                        waiting = true;
+                    }
+                }
+
+                if waiting {
+                    self.send_timeout(self.creator.stat.timer.remaining(now));
+                    self.send_timeout(self.participant.as_ref().unwrap().stat.timer.remaining(now));
+                } else {
+                    self.send_timeout(self.timers.bet * 1000);
+                }
+                self.send_deadlines();
+            }
+        }
+    }
+
+    fn send_timeout(&self, millis: u64) {
+        self.send_command(RoomCommand::Timer {
+            state_id: self.state_id.as_ref().unwrap().clone(),
+            duration: std::time::Duration::from_millis(millis),
+        });
+    }
+
+    fn send_command(&self, command: RoomCommand) {
+        if let Err(err) = self.commands.send(command) {
+            eprintln!("Couldn't send room command \n {}", err);
+        }
+    }
+
+    // Send absolute deadlines with server timestamp
+    // so that clients can show accurate countdowns.
+    fn send_deadlines(&self) {
+        let now = now_millis();
+        let creator = &self.creator;
+        let part = self.participant.as_ref().unwrap();
+
+        creator.send_message(
+            &ServerResponse::new(
+                ResponseType::Deadline, 
+                ResponseValue::Deadline(Deadline::new(creator, part, now))
+            ));
+
+        part.send_message(
+            &ServerResponse::new(
+                ResponseType::Deadline, 
+                ResponseValue::Deadline(Deadline::new(part, creator, now))
+            ));
     }
 
     pub fn init_game(&mut self) {
-        if let None = self.participant {
+        if self.participant.is_none() {
             eprintln!("Tried to init a game with no participant.");
             return;
         }
         self.send_env_variables();
         self.init_cards_and_send();
         self.set_state_id_and_send();
-
-        // Set timeout by sending request through internal channel
-        let req_timeout =InternalRequest::new_json(
-            IntReqType::TimeOut, 
-            IntReqValue::TimeOut(TimeOut{duration: std::time::Duration::from_secs(BET_TIME), state_id: self.state_id.as_ref().unwrap().clone()})
-        ).expect("Failed to create internal request");
-        if let Err(err) = self.internal_sender.send(Ok(Message::text(req_timeout))) {
-            eprintln!("Couldn't send internal request : {}", err);
-        }
     }
 
     fn send_env_variables(&self) {
-        if let None = self.participant {
+        if self.participant.is_none() {
             eprintln!("Invalid operation participant is empty");
             return;
         }
 
-        let res = ServerResponse::new_json(
+        for user in [&self.creator, self.participant.as_ref().unwrap()].iter() {
+            user.send_message(&self.env_response(&user.id));
+        }
+    }
+
+    fn env_response(&self, uid: &str) -> ServerResponse {
+        ServerResponse::new(
             ResponseType::Env, 
             ResponseValue::Env(
                 EnvVar{
                     hp: DEFAULT_HP,
-                    bet_time: BET_TIME,
-                    result_time: SHOWDOWN_TIME,
+                    stakes: self.rules.stakes,
+                    bet_time: self.timers.bet,
+                    time_bank: self.timers.time_bank,
+                    result_time: self.timers.showdown,
+                    lobby_time: self.timers.lobby,
+                    reconnect_time: self.timers.reconnect,
+                    best_of: self.series.best_of,
+                    opp_name: self.opponent_of(uid).map(|opp| opp.name().to_string()).unwrap_or_default(),
                 }, 
             )
-        ).expect("Failed to create server response");
-        self.creator.send_message(&res);
-        self.participant.as_ref().unwrap().send_message(&res);
+        )
     }
 
     fn init_cards_and_send(&mut self) {
@@ -148,60 +464,412 @@ impl Game {
         self.creator.stat.cards = self.card_pool.poll_cards(2).unwrap();
         self.participant.as_mut().unwrap().stat.cards = self.card_pool.poll_cards(2).unwrap();
 
-        let res_community = ServerResponse::new_json(
+        // Sitting out player is skipped for blind
+        for user in [&mut self.creator, self.participant.as_mut().unwrap()].iter_mut() {
+            user.stat.blind = if user.sitting_out { 0 } else { self.rules.stakes };
+        }
+
+        let res_community = ServerResponse::new(
             ResponseType::Community, 
             ResponseValue::Card(self.community.clone())
-        ).expect("Failed to create server response");
+        );
         self.creator.send_message(&res_community);
         self.participant.as_ref().unwrap().send_message(&res_community);
+        self.broadcast_spectators(&res_community);
 
-        let res_creator = ServerResponse::new_json(
+        let res_creator = ServerResponse::new(
             ResponseType::Hand, 
             ResponseValue::Card(self.creator.stat.cards.clone())
-        ).expect("Failed to create server response");
+        );
         self.creator.send_message(&res_creator);
 
-        let res_part = ServerResponse::new_json(
+        let res_part = ServerResponse::new(
             ResponseType::Hand, 
             ResponseValue::Card(self.participant.as_ref().unwrap().stat.cards.clone())
-        ).expect("Failed to create server response");
+        );
         self.participant.as_ref().unwrap().send_message(&res_part);
     }
 
-    pub fn broadcast_message(&self, msg: &str) {
-        if let None = self.participant {
+    // Spectators never get hole cards before showdown,
+    // thus only call this method with public information.
+    pub fn broadcast_spectators(&self, msg: &ServerResponse) {
+        for spectator in self.spectators.iter() {
+            spectator.send_message(msg);
+        }
+    }
+
+    pub fn add_spectator(
+        &mut self,
+        id: String, 
+        sender: Outbox,
+        client: ClientInfo,
+        delay: u64,
+    ) {
+        let spectator = Spectator::new(id, sender, client, delay);
+
+        // Send current state so that late spectator can follow the game
+        if let Some(state_id) = self.state_id.as_ref() {
+            spectator.send_message(
+                &ServerResponse::new(
+                    ResponseType::State,
+                    ResponseValue::State((self.state, state_id.clone()))
+                ));
+            spectator.send_message(
+                &ServerResponse::new(
+                    ResponseType::Community, 
+                    ResponseValue::Card(self.community.clone())
+                ));
+        }
+        spectator.send_message(&self.chat_history(None));
+
+        self.spectators.push(spectator);
+    }
+
+    pub fn remove_spectator(&mut self, id: &str) {
+        self.spectators.retain(|spectator| spectator.id != id);
+    }
+
+    fn user(&self, uid: &str) -> Option<&User> {
+        if self.creator.id == uid {
+            return Some(&self.creator);
+        }
+        self.participant.as_ref().filter(|user| user.id == uid)
+    }
+
+    fn user_mut(&mut self, uid: &str) -> Option<&mut User> {
+        if self.creator.id == uid {
+            return Some(&mut self.creator);
+        }
+        self.participant.as_mut().filter(|user| user.id == uid)
+    }
+
+    pub fn opponent_of(&self, uid: &str) -> Option<&User> {
+        if self.creator.id == uid {
+            self.participant.as_ref()
+        } else {
+            Some(&self.creator)
+        }
+    }
+
+    // Resume tokens of seated players
+    pub fn tokens(&self) -> Vec<String> {
+        let mut tokens = vec![self.creator.token.clone()];
+        tokens.extend(self.participant.as_ref().map(|user| user.token.clone()));
+        tokens
+    }
+
+    // Mark user as disconnected and start grace period.
+    // Returns false if disconnection is from outdated session.
+    pub fn disconnect_user(&mut self, uid: &str, session: u32) -> bool {
+        let user = if let Some(user) = self.user_mut(uid) {
+            user
+        } else {
+            return false;
+        };
+        if user.session != session {
+            return false;
+        }
+        user.connected = false;
+        let pending_turn = user.stat.timer.deadline.is_some();
+
+        self.send_command(RoomCommand::GraceTimer {
+            user_id: uid.to_string(),
+            session,
+            duration: std::time::Duration::from_secs(self.timers.reconnect),
+        });
+
+        let deadline = now_millis() + self.timers.reconnect * 1000;
+        self.send_connection_status(uid, Some(deadline));
+
+        // Game keeps running while player is away,
+        // thus play automatic action if it is player's turn.
+        if pending_turn {
+            let facing_raise = self.opponent_of(uid)
+                .map_or(false, |opp| opp.current_action == PlayerAction::Raise);
+            let req = UserRequest {
+                state_id: self.state_id.clone().unwrap(),
+                action: if facing_raise { PlayerAction::Fold } else { PlayerAction::Check },
+                value: Some(0),
+                text: None,
+                emote: None,
+            };
+            let pending = self.receive_player_action(uid, req);
+            self.pending_next_state(pending);
+        }
+        true
+    }
+
+    // Tell opponent whether player is connected.
+    // Deadline is given when player has disconnected.
+    fn send_connection_status(&self, uid: &str, return_deadline: Option<u64>) {
+        if let Some(opponent) = self.opponent_of(uid) {
+            opponent.send_message(
+                &ServerResponse::new(
+                    ResponseType::Connection, 
+                    ResponseValue::Connection(ConnectionStatus {
+                        opp_connected: return_deadline.is_none(),
+                        return_deadline,
+                        seconds_left: return_deadline.map(|_| self.timers.reconnect),
+                    })
+                ));
+        }
+    }
+
+    // User has not come back in grace period
+    pub fn is_grace_expired(&self, uid: &str, session: u32) -> bool {
+        self.user(uid).map_or(false, |user| !user.connected && user.session == session)
+    }
+
+    // Socket of outdated session may still deliver frames after user has resumed
+    pub fn is_current_session(&self, uid: &str, session: u32) -> bool {
+        self.user(uid).map_or(false, |user| user.session == session)
+    }
+
+    // Attach new agent to user with given token.
+    // Returns user id and new session.
+    pub fn resume_user(&mut self, token: &str, agent: Box<dyn PlayerAgent>) -> Option<(String, u32)> {
+        let user = if self.creator.token == token {
+            &mut self.creator
+        } else {
+            self.participant.as_mut().filter(|user| user.token == token)?
+        };
+
+        user.agent = agent;
+        user.session += 1;
+        user.connected = true;
+        let (uid, session) = (user.id.clone(), user.session);
+
+        self.resend_state(&uid);
+        self.send_connection_status(&uid, None);
+        Some((uid, session))
+    }
+
+    // Send current hand state to user who has resumed the game
+    fn resend_state(&self, uid: &str) {
+        let user = if self.creator.id == uid {
+            &self.creator
+        } else {
+            self.participant.as_ref().unwrap()
+        };
+
+        user.send_message(&self.env_response(uid));
+        self.send_snapshot(uid);
+        let opp_seat = if self.creator.id == uid { Seat::Participant } else { Seat::Creator };
+        user.send_message(&self.chat_history(Some(opp_seat).filter(|_| user.muted)));
+    }
+
+    // Messages of muted seat are left out like live chat
+    fn chat_history(&self, muted: Option<Seat>) -> ServerResponse {
+        let chat = self.chat.iter()
+            .filter(|chat| Some(chat.seat) != muted)
+            .cloned()
+            .collect();
+        ServerResponse::new(
+            ResponseType::ChatHistory, 
+            ResponseValue::ChatHistory(chat)
+        )
+    }
+
+    // Chat is not a part of betting thus it is accepted in any state
+    fn receive_chat(&mut self, uid: &str, req: UserRequest) {
+        let seat = if self.creator.id == uid { Seat::Creator } else { Seat::Participant };
+
+        match req.action {
+            PlayerAction::Mute | PlayerAction::Unmute => {
+                if let Some(user) = self.user_mut(uid) {
+                    user.muted = req.action == PlayerAction::Mute;
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        let text = req.text.map(|text| text.trim().to_string()).filter(|text| !text.is_empty());
+        if text.is_none() && req.emote.is_none() {
+            return;
+        }
+        if text.as_ref().map_or(false, |text| text.chars().count() > CHAT_LENGTH) {
+            if let Some(user) = self.user_mut(uid) {
+                user.send_message(
+                    &ServerResponse::new(
+                        ResponseType::Error, 
+                        ResponseValue::Message(format!("Chat message is longer than {} characters", CHAT_LENGTH))
+                    ));
+            }
             return;
         }
 
-        self.participant.as_ref().unwrap().send_message(msg);
-        self.creator.send_message(msg);
+        let chat = ChatMessage {
+            seat,
+            text,
+            emote: req.emote,
+            time: now_millis(),
+        };
+        let res = ServerResponse::new(ResponseType::Chat, ResponseValue::Chat(chat.clone()));
+
+        // Sender also gets the message as a confirmation
+        if let Some(user) = self.user_mut(uid) {
+            user.send_message(&res);
+        }
+        if let Some(opponent) = self.opponent_of(uid) {
+            if !opponent.muted {
+                opponent.send_message(&res);
+            }
+        }
+        self.broadcast_spectators(&res);
+
+        self.chat.push(chat);
+        if self.chat.len() > CHAT_HISTORY {
+            self.chat.remove(0);
+        }
+    }
+
+    // Players whose outbox merged away game updates get a snapshot instead
+    pub fn resync_stale(&self) {
+        let stale = [Some(&self.creator), self.participant.as_ref()].iter()
+            .flatten()
+            .filter(|user| user.agent.take_stale())
+            .map(|user| user.id.clone())
+            .collect::<Vec<String>>();
+        for uid in stale {
+            self.send_snapshot(&uid);
+        }
     }
 
-    pub fn next_state(&mut self, state_id : &str) {
-        // This should work in normal cases.
-        // However it might be used in not desired
-        // situations. Then error handing should be properly 
-        // implemented.
-        if self.state_id.as_ref().unwrap() != state_id {
-            //State id not equal, ignoring request
+    // Send everything that client needs to rebuild the game
+    // so that client can recover from missed messages.
+    pub fn send_snapshot(&self, uid: &str) {
+        let part = if let Some(part) = self.participant.as_ref() {
+            part
+        } else {
+            return;
+        };
+
+        let (user, opp, user_won, opp_won) = if self.creator.id == uid {
+            (&self.creator, part, self.series.creator_wins, self.series.participant_wins)
+        } else {
+            (part, &self.creator, self.series.participant_wins, self.series.creator_wins)
+        };
+
+        user.send_message(
+            &ServerResponse::new(
+                ResponseType::Snapshot, 
+                ResponseValue::Snapshot(Box::new(Snapshot {
+                    state: self.state,
+                    state_id: self.state_id.clone(),
+                    deadline: Deadline::new(user, opp, now_millis()),
+                    community: self.community.clone(),
+                    hand: user.stat.cards.clone(),
+                    bet: user.stat.bet,
+                    opp_bet: opp.stat.bet,
+                    total_bet: self.get_total_bet(),
+                    hp: user.stat.hp,
+                    opp_hp: opp.stat.hp,
+                    action: user.current_action,
+                    opp_action: opp.last_action,
+                    fold: user.stat.fold,
+                    opp_fold: opp.stat.fold,
+                    sitting_out: user.sitting_out,
+                    opp_sitting_out: opp.sitting_out,
+                    opp_connected: opp.connected,
+                    series: SeriesScore {
+                        wins: user_won,
+                        opp_wins: opp_won,
+                        best_of: self.series.best_of,
+                        decided: self.series.is_decided(),
+                    },
+                }))
+            ));
+    }
+
+    // Room cancels timers of previous states before they expire,
+    // thus timer always belongs to current state.
+    pub fn next_state(&mut self) {
+        // Nobody voted for a rematch in time, close the room
+        if let GameState::Lobby = self.state {
+            self.end_game();
             return;
         }
+
+        // Player whose deadline has passed used up whole time bank.
+        // If any player still has time left, wait for the player's own timeout.
+        let betting = matches!(self.state, GameState::Flop | GameState::Turn | GameState::River);
+        if betting {
+            let now = now_millis();
+            let mut status_changed = false;
+            let mut waiting = false;
+
+            for user in [&mut self.creator, self.participant.as_mut().unwrap()].iter_mut() {
+                if user.stat.timer.is_expired(now) {
+                    user.stat.timer.stop(now);
+                    status_changed |= user.register_timeout();
+                }
+                waiting |= user.stat.timer.deadline.is_some();
+            }
+
+            if status_changed {
+                self.send_status();
+            }
+            if waiting {
+                return;
+            }
+        }
+
         self.no_response_check();
         self.end_bet();
-        self.change_state(self.state);
+
+        // Sitting out player might have folded against raise
+        let folded = self.creator.stat.fold || self.participant.as_ref().unwrap().stat.fold;
+        if betting && folded {
+            self.change_state(GameState::Fold);
+        } else {
+            self.change_state(self.state);
+        }
     }
 
     fn no_response_check(&mut self) {
         let user = &mut self.creator;
         let opp = self.participant.as_mut().unwrap();
 
-        if let PlayerAction::None = user.current_action {
+        Self::auto_action(user, opp);
+        Self::auto_action(opp, user);
+    }
+
+    // Missing action is check, however sitting out or disconnected player
+    // folds when facing a bet.
+    fn auto_action(user: &mut User, opp: &mut User) {
+        if user.is_away() && opp.current_action == PlayerAction::Raise && user.current_action != PlayerAction::Raise {
+            user.fold();
+            user.current_action = PlayerAction::Fold;
+            // Raise of 0 leaves nothing to revert
+            opp.stat.bet = opp.stat.bet.saturating_sub(1);
+        } else if let PlayerAction::None = user.current_action {
             user.current_action = PlayerAction::Check;
         }
+    }
 
-        if let PlayerAction::None = opp.current_action {
-            opp.current_action = PlayerAction::Check;
-        }
+    // Tell both players whether each player is sitting out
+    fn send_status(&self) {
+        let creator = &self.creator;
+        let part = self.participant.as_ref().unwrap();
+
+        creator.send_message(
+            &ServerResponse::new(
+                ResponseType::SitOut, 
+                ResponseValue::SitOut(SitOutStatus {
+                    sitting_out: creator.sitting_out,
+                    opp_sitting_out: part.sitting_out,
+                })
+            ));
+
+        part.send_message(
+            &ServerResponse::new(
+                ResponseType::SitOut, 
+                ResponseValue::SitOut(SitOutStatus {
+                    sitting_out: part.sitting_out,
+                    opp_sitting_out: creator.sitting_out,
+                })
+            ));
     }
 
     pub fn pending_next_state(&mut self, pending: Pending) {
@@ -226,7 +894,17 @@ impl Game {
                 self.calculate_showdown();
             }
             GameState::ShowDown => {
+                // Someone has lost every hp, wait for rematch votes
+                if self.is_game_over() {
+                    self.state = GameState::Lobby;
+                } else {
+                    self.state = GameState::Flop;
+                }
+            }
+            GameState::Lobby => {
                 self.state = GameState::Flop;
+                self.reset_stats();
+                self.send_env_variables();
             }
         }
 
@@ -236,41 +914,60 @@ impl Game {
         self.set_state_id_and_send();
 
         if let Some(card) = new_card {
-            let res = ServerResponse::new_json(ResponseType::Community, ResponseValue::Card(vec![card])).expect("Failed to create server response");
+            let res = ServerResponse::new(ResponseType::Community, ResponseValue::Card(vec![card]));
             self.creator.send_message(&res);
             self.participant.as_ref().unwrap().send_message(&res);
+            self.broadcast_spectators(&res);
         }
 
-        if let GameState::ShowDown = self.state {
-            // Send Timeout request
-            let req = InternalRequest::new_json(
-                IntReqType::TimeOut, 
-                IntReqValue::TimeOut(TimeOut {
-                    duration: std::time::Duration::from_secs(SHOWDOWN_TIME), 
-                    state_id: self.state_id.clone().unwrap()
-                })).expect("Failed to create internal request");
-            self.internal_sender.send(Ok(Message::text(req)))
-                .expect("Failed to send internal request");
-        } else if let GameState::Flop = self.state {
+        if let GameState::Flop = self.state {
             self.clear_user_bet();
             self.init_cards_and_send();
         }
     }
 
+    // Spectators and unknown ids are not seated
+    pub fn is_seated(&self, uid: &str) -> bool {
+        self.creator.id == uid || 
+            self.participant.as_ref().map_or(false, |user| user.id == uid)
+    }
+
     pub fn receive_player_action(&mut self, uid: &str, req: UserRequest) -> Pending {
+        // Only seated players can act
+        if !self.is_seated(uid) {
+            return Pending(None);
+        }
 
-        // If state is different from current state,
-        // It means request is outdated or modified.
-        if &req.state_id != self.state_id.as_ref().unwrap() {
+        // Creator can chat while waiting for participant
+        if let PlayerAction::Message | PlayerAction::Mute | PlayerAction::Unmute = req.action {
+            self.receive_chat(uid, req);
             return Pending(None);
         }
 
         // If room is not complete, return
-        if let None = self.participant {
+        if self.participant.is_none() {
             eprintln!("Tried to retrive action while room is not complete");
             return Pending(None);
         }
+
+        // Client which missed messages doesn't know current state id,
+        // thus resync is accepted regardless of state id.
+        if let PlayerAction::Resync = req.action {
+            self.send_snapshot(uid);
+            return Pending(None);
+        }
+
+        // If state is different from current state,
+        // It means request is outdated or modified.
+        if &req.state_id != self.state_id.as_ref().unwrap() {
+            return Pending(None);
+        }
         
+        // Only rematch votes are accepted after the game is over
+        if let GameState::Lobby = self.state {
+            return self.receive_rematch_vote(uid, req.action);
+        }
+
         let user: &mut User;
         let opp: &mut User;
 
@@ -283,6 +980,7 @@ impl Game {
         }
 
         let mut pending = Pending(None);
+        let mut raised = false;
 
         // TODO :: Make it work
         // Calculate according to given player action.
@@ -291,15 +989,6 @@ impl Game {
             PlayerAction::Fold => {
                 user.fold();
             }
-            PlayerAction::Message => {
-                if uid == user.id {
-                    opp.send_message("Ping from opponent");
-                }
-                // participant's turn
-                else {
-                    user.send_message("Ping from opponent");
-                }
-            }
             // For Check, Raise, Call(Raise)
             _ => {
                 if let Some(amount) = req.value {
@@ -307,34 +996,23 @@ impl Game {
 
                     if let PlayerAction::Raise = req.action {
                         opp.send_message(
-                            &ServerResponse::new_json(
+                            &ServerResponse::new(
                                 ResponseType::Raise, 
                                 ResponseValue::Raise(amount)
-                            ).expect("Failed to create server response"));
-
-                        user.send_message(
-                            &ServerResponse::new_json(
-                                ResponseType::Delay, 
-                                ResponseValue::Number(BET_TIME as i32)
-                            ).expect("Failed to create server response"));
-
-                        // And lengthen timeout period.
-                        // Ths enables tokio tasks that wait for delay, can
-                        // halt their action after the delay.
-                        self.state_extend = true;
-
-                        // TODO IMPORTANT
-                        // Should this code go to next_state function ?
-                        let req_timeout =InternalRequest::new_json(
-                            IntReqType::TimeOut, 
-                            IntReqValue::TimeOut(TimeOut{duration: std::time::Duration::from_secs(BET_TIME), state_id: self.state_id.as_ref().unwrap().clone()})
-                        ).expect("Failed to create internal request");
-                        let result = self.internal_sender.send(Ok(Message::text(req_timeout)));
-                        match result {
-                            Ok(()) => {}
-                            Err(_) => {
-                                eprintln!("Couldn't send internal request");
-                            }
+                            ));
+                        user.last_action = PlayerAction::Raise;
+
+                        // Opponent should respond to raise with a new turn.
+                        // Sitting out or disconnected player folds right away.
+                        if opp.is_away() {
+                            opp.fold();
+                            opp.current_action = PlayerAction::Fold;
+                        } else {
+                            // Time opponent has taken so far is charged before new turn
+                            let now = now_millis();
+                            opp.stat.timer.stop(now);
+                            opp.stat.timer.start(now);
+                            raised = true;
                         }
                     }
                 } else {
@@ -344,87 +1022,151 @@ impl Game {
             }
         }
 
-        if let PlayerAction::Message = req.action{}
-        else {
-            if req.action == PlayerAction::Call {
-                user.current_action = PlayerAction::Raise;
-            } else {
-                user.current_action = req.action;
-            }
+        // Player's turn is over, time taken over bet time is deducted from time bank
+        user.stat.timer.stop(now_millis());
+        // Automatic action for disconnected player doesn't bring player back
+        let status_changed = user.connected && user.register_action();
+
+        if req.action == PlayerAction::Call {
+            user.current_action = PlayerAction::Raise;
+        } else {
+            user.current_action = req.action;
+        }
 
 
-            let mut bet_end = false;
-            // TODO :: Check if server can change the state 
-            // thus make Pending current state.
-            // if all players' have bet.
-            // change the state.
-            // Currently it reverts  bet by hard code if it gets non limit hold'em
-            // it get's different and should be re-implemented.
-            if user.current_action == opp.current_action {
-                bet_end = true;
-                pending = Pending(Some(self.state));
-            } else if user.current_action == PlayerAction::Fold {
-                bet_end = true;
-                if let PlayerAction::Raise = opp.current_action {
-                    opp.stat.bet -= 1;
-                }
-                pending = Pending(Some(GameState::Fold));
-            } else if opp.current_action == PlayerAction::Fold {
-                bet_end = true;
-                if let PlayerAction::Raise = user.current_action {
-                    user.stat.bet -= 1;
-                }
-                pending = Pending(Some(GameState::Fold));
+        let mut bet_end = false;
+        // TODO :: Check if server can change the state 
+        // thus make Pending current state.
+        // if all players' have bet.
+        // change the state.
+        // Currently it reverts  bet by hard code if it gets non limit hold'em
+        // it get's different and should be re-implemented.
+        if user.current_action == opp.current_action {
+            bet_end = true;
+            pending = Pending(Some(self.state));
+        } else if user.current_action == PlayerAction::Fold {
+            bet_end = true;
+            if let PlayerAction::Raise = opp.current_action {
+                opp.stat.bet = opp.stat.bet.saturating_sub(1);
+            }
+            pending = Pending(Some(GameState::Fold));
+        } else if opp.current_action == PlayerAction::Fold {
+            bet_end = true;
+            if let PlayerAction::Raise = user.current_action {
+                user.stat.bet = user.stat.bet.saturating_sub(1);
             }
+            pending = Pending(Some(GameState::Fold));
+        }
 
-            if bet_end {
-                self.end_bet();
+        if bet_end {
+            self.end_bet();
+        } else {
+            if raised {
+                let remaining = opp.stat.timer.remaining(now_millis());
+                self.send_timeout(remaining);
             }
+            self.send_deadlines();
+        }
+
+        if status_changed {
+            self.send_status();
         }
 
         pending
     }
 
+    fn receive_rematch_vote(&mut self, uid: &str, action: PlayerAction) -> Pending {
+        if action != PlayerAction::Rematch {
+            return Pending(None);
+        }
+
+        let user: &mut User;
+        let opp: &mut User;
+
+        if uid == self.creator.id {
+            user = &mut self.creator;
+            opp = self.participant.as_mut().unwrap();
+        } else {
+            user = self.participant.as_mut().unwrap();
+            opp = &mut self.creator;
+        }
+
+        user.current_action = PlayerAction::Rematch;
+        opp.send_message(
+            &ServerResponse::new(
+                ResponseType::Rematch, 
+                ResponseValue::Message("Opponent wants a rematch".to_string())
+            ));
+
+        // Both players voted, start a new game in the same room
+        if opp.current_action == PlayerAction::Rematch {
+            return Pending(Some(GameState::Lobby));
+        }
+
+        Pending(None)
+    }
+
     pub fn join_game(
         &mut self,
         id: String, 
-        sender: mpsc::UnboundedSender<Result<Message, warp::Error>>
+        agent: Box<dyn PlayerAgent>
     ) {
-        self.participant.replace(User::new(id, sender));
+        let user = User::new(id, agent, &self.timers);
+        user.send_message(&self.chat_history(Some(Seat::Creator).filter(|_| user.muted)));
+        self.participant.replace(user);
     }
 
-    fn end_bet(&self) {
+    fn end_bet(&mut self) {
 
-        if let None = self.participant {
+        if self.participant.is_none() {
             return;
         }
 
+        // Both actions are revealed to opponents
+        self.creator.last_action = self.creator.current_action;
+        let part = self.participant.as_mut().unwrap();
+        part.last_action = part.current_action;
+
         let total_bet = self.get_total_bet();
         self.creator.send_message(
-            &ServerResponse::new_json(
+            &ServerResponse::new(
                 ResponseType::BetResult, 
                 ResponseValue::BetResult(BetResult{opponent_action: self.participant.as_ref().unwrap().current_action, total_bet})
-            ).expect("Failed to create server response")
+            )
         );
 
         self.participant.as_ref().unwrap().send_message(
-            &ServerResponse::new_json(
+            &ServerResponse::new(
                 ResponseType::BetResult, 
                 ResponseValue::BetResult(BetResult{opponent_action: self.creator.current_action, total_bet})
-            ).expect("Failed to create server response")
+            )
+        );
+
+        self.broadcast_spectators(
+            &ServerResponse::new(
+                ResponseType::BetResult, 
+                ResponseValue::SpectatorBet(SpectatorBet{
+                    creator_action: self.creator.current_action,
+                    participant_action: self.participant.as_ref().unwrap().current_action,
+                    total_bet,
+                })
+            )
         );
     }
 
     // Prefere this method rather than manually adding two bets
     fn get_total_bet(&self) -> u32 {
-        // NOTE Hard coded initial bet which is 2 in this case.
+        // NOTE Sitting out player doesn't put blind.
         // Due to mutural refernce rule total_bet should be boxed into variable
-        self.participant.as_ref().unwrap().stat.bet + self.creator.stat.bet + 2
+        let part = &self.participant.as_ref().unwrap().stat;
+        part.bet.saturating_add(part.blind)
+            .saturating_add(self.creator.stat.bet)
+            .saturating_add(self.creator.stat.blind)
     }
 
     fn add_community(&mut self) -> Card {
         if let Some(card) = self.card_pool.poll_card() {
-            self.community.push(card.clone());
+            self.community.push(card);
             card
         } else {
             panic!("This should not happen. This error occured because every possible card in card pools has been polled");
@@ -432,7 +1174,7 @@ impl Game {
     }
 
     fn clear_user_bet(&mut self) {
-        if let None =self.participant {
+        if self.participant.is_none() {
             eprintln!("Invalid work flow. Cannot call function clear_user_bet when participant is not empty");
             return;
         }
@@ -442,8 +1184,22 @@ impl Game {
         self.participant.as_mut().unwrap().stat.bet = 0;
         self.participant.as_mut().unwrap().stat.fold = false;
     }
+    fn reset_stats(&mut self) {
+        if self.participant.is_none() {
+            eprintln!("Invalid work flow. Cannot call function reset_stats when participant is empty");
+            return;
+        }
+
+        self.creator.stat = PlayerStat::new(&self.timers);
+        self.participant.as_mut().unwrap().stat = PlayerStat::new(&self.timers);
+    }
+
+    fn is_game_over(&self) -> bool {
+        self.creator.stat.hp == 0 || self.participant.as_ref().unwrap().stat.hp == 0
+    }
+
     fn clear_user_action(&mut self) {
-        if let None =self.participant {
+        if self.participant.is_none() {
             eprintln!("Invalid work flow Cannot call function clear_user_action when participant is not empty");
             return;
         }
@@ -481,31 +1237,7 @@ impl Game {
         // If no fold is found then calculate normally.
         // else don't do comparison
         if let Ordering::Equal = cmp_result {
-            cmp_result = (user_comb as u8).cmp(&(part_comb as u8));
-        }
-
-        if let Ordering::Equal =  cmp_result.clone() {
-            // if both player is high card,
-            // compare both numbers.
-            // and set comparison again.
-            if let Some(number) = &user_meta {
-                let user_number = number.parse::<i32>().unwrap_or(0);
-                let part_number = part_meta.clone().unwrap_or("0".to_string()).parse::<i32>().unwrap_or(0);
-
-                let meta_result = user_number.cmp(&part_number).reverse();
-                match meta_result {
-                    // user wins
-                    Ordering::Greater => {
-                        cmp_result = Ordering::Greater;
-                    }
-                    // participant wins
-                    Ordering::Less => {
-                        cmp_result = Ordering::Less;
-                    }
-                    // draws or both is high number
-                    Ordering::Equal => {}
-                }
-            }
+            cmp_result = CombinationBuilder::compare_combination(user_comb, &user_meta, part_comb, &part_meta);
         }
 
         // Cached participant user struct
@@ -540,7 +1272,7 @@ impl Game {
             _ => {}
         }
         let to_creator_response = 
-            ServerResponse::new_json(
+            ServerResponse::new(
                 ResponseType::RoundResult, 
                 ResponseValue::RoundResult(RoundResult {
                     win: user_win_check,
@@ -553,10 +1285,31 @@ impl Game {
                     hp: self.creator.stat.hp,
                     opp_hp: self.participant.as_ref().unwrap().stat.hp,
                 })
-            ).expect("Failed to create server reseponse");
+            );
+
+        // Spectators see round result from creator's perspective.
+        // Folded hand is never revealed, thus its combination
+        // is hidden as the lowest one without meta.
+        let creator_fold = self.creator.stat.fold;
+        let part_fold = self.participant.as_ref().unwrap().stat.fold;
+        let to_spectator_response = 
+            ServerResponse::new(
+                ResponseType::RoundResult, 
+                ResponseValue::RoundResult(RoundResult {
+                    win: user_win_check,
+                    fold: creator_fold,
+                    opp_fold: part_fold,
+                    comb: if creator_fold { CardCombination::HighCard } else { user_comb },
+                    user_meta: user_meta.clone().filter(|_| !creator_fold),
+                    opp_comb: if part_fold { CardCombination::HighCard } else { part_comb },
+                    opp_meta: opp_meta.clone().filter(|_| !part_fold),
+                    hp: self.creator.stat.hp,
+                    opp_hp: self.participant.as_ref().unwrap().stat.hp,
+                })
+            );
 
         let to_part_response = 
-            ServerResponse::new_json(
+            ServerResponse::new(
                 ResponseType::RoundResult, 
                 ResponseValue::RoundResult(RoundResult {
                     win: opp_win_check,
@@ -569,11 +1322,37 @@ impl Game {
                     hp: self.participant.as_ref().unwrap().stat.hp,
                     opp_hp: self.creator.stat.hp,
                 })
-            ).expect("Failed to create server reseponse");
+            );
 
         self.creator.send_message(&to_creator_response);
         self.participant.as_ref().unwrap().send_message(&to_part_response);
 
+        let participant = self.participant.as_ref().unwrap();
+        self.hands.push(HandRecord {
+            number: self.hands.len() as u32 + 1,
+            creator_won: user_win_check,
+            creator_fold: self.creator.stat.fold,
+            participant_fold: participant.stat.fold,
+            creator_comb: if self.creator.stat.fold { None } else { Some(user_comb) },
+            participant_comb: if participant.stat.fold { None } else { Some(part_comb) },
+            pot: self.get_total_bet(),
+            creator_hp: self.creator.stat.hp,
+            participant_hp: participant.stat.hp,
+        });
+
+        // Hole cards are revealed to spectators only at showdown
+        // and folded hands are not revealed at all.
+        self.broadcast_spectators(&to_spectator_response);
+        let reveal = |user: &User| if user.stat.fold { vec![] } else { user.stat.cards.clone() };
+        self.broadcast_spectators(
+            &ServerResponse::new(
+                ResponseType::Reveal, 
+                ResponseValue::Reveal(Reveal {
+                    creator_cards: reveal(&self.creator),
+                    participant_cards: reveal(self.participant.as_ref().unwrap()),
+                })
+            ));
+
         self.send_game_result();
     }
 
@@ -589,21 +1368,71 @@ impl Game {
         }
 
         let to_creator_response = 
-            ServerResponse::new_json(
+            ServerResponse::new(
                 ResponseType::GameResult, 
                 ResponseValue::GameResult(user_game_winner)
-            ).expect("Failed to create server reseponse");
+            );
 
         let to_part_response = 
-            ServerResponse::new_json(
+            ServerResponse::new(
                 ResponseType::GameResult, 
                 ResponseValue::GameResult(!user_game_winner)
-            ).expect("Failed to create server reseponse");
+            );
 
         self.creator.send_message(&to_creator_response);
         self.participant.as_ref().unwrap().send_message(&to_part_response);
 
-        self.end_game();
+        self.series.record(user_game_winner);
+        self.send_series_score();
+
+        // Room stores the game so that writing doesn't hold up the game
+        let participant = self.participant.as_ref().unwrap();
+        let record = MatchRecord {
+            game: self.series.creator_wins + self.series.participant_wins,
+            mode: self.rules.mode,
+            stakes: self.rules.stakes,
+            creator: self.creator.account.clone(),
+            participant: participant.account.clone(),
+            creator_won: user_game_winner,
+            creator_hp: self.creator.stat.hp,
+            participant_hp: participant.stat.hp,
+            hands: std::mem::take(&mut self.hands),
+            finished: now_millis(),
+        };
+        self.send_command(RoomCommand::Record(record));
+
+        // Room is closed only when the whole series is decided.
+        // Otherwise players can vote for a rematch in lobby state.
+        if self.series.is_decided() {
+            self.end_game();
+        }
+    }
+
+    fn send_series_score(&self) {
+        let to_creator_response = 
+            ServerResponse::new(
+                ResponseType::Series, 
+                ResponseValue::Series(SeriesScore {
+                    wins: self.series.creator_wins,
+                    opp_wins: self.series.participant_wins,
+                    best_of: self.series.best_of,
+                    decided: self.series.is_decided(),
+                })
+            );
+
+        let to_part_response = 
+            ServerResponse::new(
+                ResponseType::Series, 
+                ResponseValue::Series(SeriesScore {
+                    wins: self.series.participant_wins,
+                    opp_wins: self.series.creator_wins,
+                    best_of: self.series.best_of,
+                    decided: self.series.is_decided(),
+                })
+            );
+
+        self.creator.send_message(&to_creator_response);
+        self.participant.as_ref().unwrap().send_message(&to_part_response);
     }
 
     fn end_game(&mut self) {
@@ -613,50 +1442,93 @@ impl Game {
             Uuid::new_v4().to_simple().to_string()
         );
 
-        let req_timeout =InternalRequest::new_json(
-            IntReqType::GameEnd,
-            IntReqValue::None
-        ).expect("Failed to create internal request");
-        if let Err(err) = self.internal_sender.send(Ok(Message::text(req_timeout))) {
-            eprintln!("Couldn't send internal request \n {}", err);
-        };
-
+        self.send_command(RoomCommand::GameEnd);
     }
 }
 
 pub struct User {
     pub id : String,
     pub current_action: PlayerAction,
-    pub sender : mpsc::UnboundedSender<Result<Message, warp::Error>>,
+    // Action that opponent has been told about
+    pub last_action: PlayerAction,
+    pub agent : Box<dyn PlayerAgent>,
     pub stat: PlayerStat,
+    pub timeouts: u32,
+    pub sitting_out: bool,
+    // Token to resume the game after disconnection
+    pub token: String,
+    // Increased every time new connection is attached to user
+    pub session: u32,
+    pub connected: bool,
+    // Chat from opponent is not delivered
+    pub muted: bool,
+    // Stable identity of registered player across games
+    pub account: Option<Account>,
 }
 
 impl User {
     pub fn new(
         id: String, 
-        sender: mpsc::UnboundedSender<Result<Message, warp::Error>>,
+        agent: Box<dyn PlayerAgent>,
+        timers: &Timers,
     ) -> Self {
         Self {  
             id,
+            account: agent.account(),
             current_action: PlayerAction::None,
-            sender,
-            stat: PlayerStat::new(),
+            last_action: PlayerAction::None,
+            agent,
+            stat: PlayerStat::new(timers),
+            timeouts: 0,
+            sitting_out: false,
+            token: Uuid::new_v4().to_simple().to_string(),
+            session: 0,
+            connected: true,
+            muted: false,
+        }
+    }
+
+    // Name shown to opponent
+    pub fn name(&self) -> &str {
+        self.account.as_ref().map_or(GUEST_NAME, |account| account.name.as_str())
+    }
+
+    // Player who is not in the game gets automatic actions
+    pub fn is_away(&self) -> bool {
+        self.sitting_out || !self.connected
+    }
+
+    // Returns true if player has started sitting out
+    pub fn register_timeout(&mut self) -> bool {
+        self.timeouts += 1;
+        if !self.sitting_out && self.timeouts >= SIT_OUT_TIMEOUTS {
+            self.sitting_out = true;
+            return true;
+        }
+        false
+    }
+
+    // Returns true if player has come back from sitting out
+    pub fn register_action(&mut self) -> bool {
+        self.timeouts = 0;
+        if self.sitting_out {
+            self.sitting_out = false;
+            return true;
         }
+        false
     }
 
     // Bet should be incremental
     pub fn bet(&mut self, amount: u32) {
-        self.stat.bet += amount;
+        self.stat.bet = self.stat.bet.saturating_add(amount);
     }
 
     pub fn fold(&mut self) {
         self.stat.fold = true;
     }
 
-    pub fn send_message(&self, msg :&str) {
-        if let Err(err) = self.sender.send(Ok(Message::text(msg))) {
-            eprintln!("Failed to send message : \n {}", err);
-        }
+    pub fn send_message(&self, msg :&ServerResponse) {
+        self.agent.send_event(msg);
     }
 
     pub fn apply_damage(&mut self, damage: u32) {
@@ -668,20 +1540,171 @@ impl User {
     }
 }
 
+// Best-of-N score which lasts across games in the same room
+pub struct Series {
+    pub best_of: u32,
+    pub creator_wins: u32,
+    pub participant_wins: u32,
+}
+
+impl Series {
+    pub fn new(best_of: u32) -> Self {
+        Self {  
+            best_of,
+            creator_wins: 0,
+            participant_wins: 0,
+        }
+    }
+
+    pub fn record(&mut self, creator_won: bool) {
+        if creator_won {
+            self.creator_wins += 1;
+        } else {
+            self.participant_wins += 1;
+        }
+    }
+
+    // Series is decided when either player has won majority of games
+    pub fn is_decided(&self) -> bool {
+        let required = self.best_of / 2 + 1;
+        self.creator_wins >= required || self.participant_wins >= required
+    }
+}
+
+// Options given to watch route as query
+#[derive(Deserialize)]
+pub struct WatchOption {
+    // Seconds that spectator stream lags behind the game
+    pub delay: Option<u64>,
+    pub password: Option<String>,
+}
+
+impl WatchOption {
+    // No delay by default
+    pub fn delay(&self) -> u64 {
+        self.delay.unwrap_or(0).min(MAX_SPECTATOR_DELAY)
+    }
+}
+
+// Spectator only recieves public information and cannot send any actions
+pub struct Spectator {
+    pub id: String,
+    pub sender: Outbox,
+    pub client: ClientInfo,
+    pub delay: u64,
+}
+
+impl Spectator {
+    pub fn new(
+        id: String, 
+        sender: Outbox,
+        client: ClientInfo,
+        delay: u64,
+    ) -> Self {
+        Self {  
+            id,
+            sender,
+            client,
+            delay,
+        }
+    }
+
+    pub fn send_message(&self, msg :&ServerResponse) {
+        // Older client doesn't understand newer responses
+        if !self.client.accepts(msg) {
+            return;
+        }
+        if self.delay == 0 {
+            self.client.send(&self.sender, msg);
+            return;
+        }
+
+        // Delay broadcast in separate task so that game doesn't wait for it
+        let priority = Priority::of(msg);
+        let msg = self.client.encode(msg);
+        let sender = self.sender.clone();
+        let delay = self.delay;
+        tokio::task::spawn(async move {
+            tokio::time::delay_for(std::time::Duration::from_secs(delay)).await;
+            sender.send(priority, msg);
+        });
+    }
+}
+
+// Turn timer with time bank.
+// Every time unit is millisecond since unix epoch.
+pub struct TurnTimer {
+    pub bet_time: u64,
+    pub bank: u64,
+    pub turn_start: u64,
+    pub deadline: Option<u64>,
+}
+
+impl TurnTimer {
+    pub fn new(timers: &Timers) -> Self {
+        Self {  
+            bet_time: timers.bet * 1000,
+            bank: timers.time_bank * 1000,
+            turn_start: 0,
+            deadline: None,
+        }
+    }
+
+    pub fn start(&mut self, now: u64) {
+        self.turn_start = now;
+        self.deadline.replace(now + self.bet_time + self.bank);
+    }
+
+    // Stop turn and use up time bank if player took longer than bet time
+    pub fn stop(&mut self, now: u64) {
+        if self.deadline.take().is_none() {
+            return;
+        }
+
+        let used = now.saturating_sub(self.turn_start);
+        let over = used.saturating_sub(self.bet_time);
+        self.bank = self.bank.saturating_sub(over);
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        if let Some(deadline) = self.deadline {
+            deadline <= now
+        } else {
+            false
+        }
+    }
+
+    pub fn remaining(&self, now: u64) -> u64 {
+        self.deadline.unwrap_or(now).saturating_sub(now)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("System time is before unix epoch")
+        .as_millis() as u64
+}
+
 pub struct PlayerStat {
     pub fold: bool,
     pub hp: u32,
     pub bet : u32,
+    pub blind : u32,
     pub cards: Vec<Card>,
+    pub timer: TurnTimer,
 }
 
 impl PlayerStat {
-    pub fn new() -> Self {
+    pub fn new(timers: &Timers) -> Self {
         Self {  
             fold: false,
             hp: DEFAULT_HP,
             bet: 0,
+            // Blind is set at the start of every round
+            blind: 0,
             cards: vec![],
+            timer: TurnTimer::new(timers),
         }
     }
 }
@@ -695,7 +1718,7 @@ impl CardPool {
         let mut cards: Vec<Card> = vec![];
         for card_type in CardType::iter() {
             for number in 1..CARD_NUMBER {
-                cards.push(Card{card_type, number : number as u8})
+                cards.push(Card::new(card_type, number as u8))
             }
         }
         Self {  
@@ -704,7 +1727,7 @@ impl CardPool {
     }
 
     pub fn poll_card(&mut self) -> Option<Card> {
-        if self.cards.len() == 0 {return None;}
+        if self.cards.is_empty() {return None;}
 
         // TODO ::: 
         // This is not necessarily a great optimization since creation of thread local
@@ -715,7 +1738,7 @@ impl CardPool {
     }
 
     pub fn poll_cards(&mut self, count: usize) -> Option<Vec<Card>> {
-        if self.cards.len() == 0 || self.cards.len() < count {return None;}
+        if self.cards.is_empty() || self.cards.len() < count {return None;}
 
         let mut cards = vec![];
 
@@ -734,7 +1757,7 @@ impl CardPool {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, PartialOrd, Eq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, JsonSchema)]
 pub struct Card {
     pub card_type: CardType,
     pub number: u8,
@@ -761,7 +1784,13 @@ impl Ord for Card {
     }
 }
 
-#[derive(Debug ,Clone, Copy, EnumIter, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Display, Hash)]
+impl PartialOrd for Card {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug ,Clone, Copy, EnumIter, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Display, Hash, JsonSchema)]
 pub enum CardType {
     Diamond,
     Spade,
@@ -769,7 +1798,7 @@ pub enum CardType {
     Clover,
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, JsonSchema)]
 pub enum CardCombination {
     HighCard = 0,
     Pair = 1,
@@ -782,7 +1811,7 @@ pub enum CardCombination {
     Rflush = 8,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Copy, Clone, JsonSchema)]
 pub enum PlayerAction {
     None,
     Message,
@@ -790,26 +1819,47 @@ pub enum PlayerAction {
     Check,
     Raise,
     Call, 
+    Rematch,
+    Resync,
+    Mute,
+    Unmute,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct UserRequest {
     pub state_id: String,
     pub action: PlayerAction,
     pub value: Option<u32>,
+    // Chat text for message action
+    pub text: Option<String>,
+    pub emote: Option<Emote>,
 }
 
-impl UserRequest{
-    pub fn dummy() -> Self {
-        Self {
-            state_id: "".to_string(),
-            action: PlayerAction::None,
-            value: None,
-        }
-    }
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+pub enum Emote {
+    Hello,
+    GoodLuck,
+    WellPlayed,
+    Thanks,
+    Oops,
+    Thinking,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+pub enum Seat {
+    Creator,
+    Participant,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct ChatMessage {
+    pub seat: Seat,
+    pub text: Option<String>,
+    pub emote: Option<Emote>,
+    pub time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, JsonSchema)]
 pub enum ResponseType {
     Env,
     State,
@@ -818,109 +1868,106 @@ pub enum ResponseType {
     Message,
     Error,
     RoomId,
+    Token,
     Raise,
-    Delay,
+    Deadline,
+    SitOut,
+    Connection,
+    Snapshot,
+    Rooms,
+    Lobby,
+    InviteCode,
+    Queue,
+    Chat,
+    ChatHistory,
+    Hello,
     BetResult,
     RoundResult,
     GameResult,
+    Series,
+    Rematch,
+    Reveal,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct ServerResponse {
     pub response_type: ResponseType,
     pub value: ResponseValue,
 }
 
 impl ServerResponse{
-    pub fn new_json(response_type: ResponseType, value: ResponseValue) -> Result<String, serde_json::Error> {
-        serde_json::to_string(&ServerResponse {
+    pub fn new(response_type: ResponseType, value: ResponseValue) -> Self {
+        Self {
             response_type,
             value
-        })
+        }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub enum ResponseValue {
     Env(EnvVar),
     State(( GameState , String)),
     BetResult(BetResult),
+    SpectatorBet(SpectatorBet),
     RoundResult(RoundResult),
+    Reveal(Reveal),
     GameResult(bool),
+    Series(SeriesScore),
     Message(String),
     Card(Vec<Card>),
     Raise(u32),
-    Number(i32),
+    Deadline(Deadline),
+    SitOut(SitOutStatus),
+    Connection(ConnectionStatus),
+    Snapshot(Box<Snapshot>),
+    Rooms(Vec<RoomInfo>),
+    Lobby(LobbyEvent),
+    Chat(ChatMessage),
+    ChatHistory(Vec<ChatMessage>),
+    Hello(ServerHello),
 }
 
 pub struct Pending(Option<GameState>);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 pub struct EnvVar {
     hp: u32,
+    pub stakes: u32,
     bet_time: u64,
+    time_bank: u64,
     result_time: u64,
+    lobby_time: u64,
+    reconnect_time: u64,
+    best_of: u32,
+    // Display name of opponent
+    opp_name: String,
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, JsonSchema)]
 pub enum GameState {
     Flop,
     Turn,
     River,
     ShowDown,
     Fold,
+    Lobby,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct InternalRequest {
-    pub request_type :IntReqType,
-    pub value: IntReqValue,
-}
-
-impl InternalRequest {
-    pub fn dummy() -> Self {
-        Self {
-            request_type: IntReqType::None,
-            value: IntReqValue::None,
-        }
-    }
-
-    pub fn new_json(request_type: IntReqType, value: IntReqValue) -> Result<String, serde_json::Error> {
-        serde_json::to_string(&InternalRequest {
-            request_type,
-            value
-        })
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum IntReqType {
-    None,
-    Message,
-    TimeOut,
-    GameEnd,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum IntReqValue {
-    None,
-    Message(String),
-    TimeOut(TimeOut),
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TimeOut {
-    pub duration: std::time::Duration,
-    pub state_id: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct BetResult {
     pub opponent_action: PlayerAction,
     pub total_bet : u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct SpectatorBet {
+    pub creator_action: PlayerAction,
+    pub participant_action: PlayerAction,
+    pub total_bet : u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct RoundResult {
     pub win: Option<bool>,
     pub fold: bool,
@@ -933,15 +1980,118 @@ pub struct RoundResult {
     pub opp_hp : u32,
 }
 
+// Deadlines are None when player doesn't have to act
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct Deadline {
+    pub server_time: u64,
+    pub deadline: Option<u64>,
+    pub time_bank: u64,
+    pub opp_deadline: Option<u64>,
+    pub opp_time_bank: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct SitOutStatus {
+    pub sitting_out: bool,
+    pub opp_sitting_out: bool,
+}
+
+impl Deadline {
+    pub fn new(user: &User, opp: &User, now: u64) -> Self {
+        Self {
+            server_time: now,
+            deadline: user.stat.timer.deadline,
+            time_bank: user.stat.timer.bank,
+            opp_deadline: opp.stat.timer.deadline,
+            opp_time_bank: opp.stat.timer.bank,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct Snapshot {
+    pub state: GameState,
+    pub state_id: Option<String>,
+    pub deadline: Deadline,
+    pub community: Vec<Card>,
+    pub hand: Vec<Card>,
+    pub bet: u32,
+    pub opp_bet: u32,
+    pub total_bet: u32,
+    pub hp: u32,
+    pub opp_hp: u32,
+    pub action: PlayerAction,
+    pub opp_action: PlayerAction,
+    pub fold: bool,
+    pub opp_fold: bool,
+    pub sitting_out: bool,
+    pub opp_sitting_out: bool,
+    pub opp_connected: bool,
+    pub series: SeriesScore,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct ConnectionStatus {
+    pub opp_connected: bool,
+    pub return_deadline: Option<u64>,
+    pub seconds_left: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct SeriesScore {
+    pub wins: u32,
+    pub opp_wins: u32,
+    pub best_of: u32,
+    pub decided: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct Reveal {
+    pub creator_cards: Vec<Card>,
+    pub participant_cards: Vec<Card>,
+}
+
 pub struct CombinationBuilder;
 
 impl CombinationBuilder {
+    // Compare two results of get_highest_combination.
+    // Greater means user wins and Less means opponent wins.
+    pub fn compare_combination(user_comb: CardCombination, user_meta: &Option<String>, opp_comb: CardCombination, opp_meta: &Option<String>) -> Ordering {
+        let mut cmp_result = (user_comb as u8).cmp(&(opp_comb as u8));
+
+        if let Ordering::Equal = cmp_result {
+            // if both player is high card,
+            // compare both numbers.
+            // and set comparison again.
+            if let Some(number) = user_meta {
+                let user_number = number.parse::<i32>().unwrap_or(0);
+                let opp_number = opp_meta.clone().unwrap_or_else(|| "0".to_string()).parse::<i32>().unwrap_or(0);
+
+                let meta_result = user_number.cmp(&opp_number).reverse();
+                match meta_result {
+                    // user wins
+                    Ordering::Greater => {
+                        cmp_result = Ordering::Greater;
+                    }
+                    // opponent wins
+                    Ordering::Less => {
+                        cmp_result = Ordering::Less;
+                    }
+                    // draws or both is high number
+                    Ordering::Equal => {}
+                }
+            }
+        }
+
+        cmp_result
+    }
+
     pub fn get_highest_combination(mut cards : Vec<Card>) -> (CardCombination, Option<String>) {
         if cards.len() <= 1 {
             panic!("Invalid card vector given to function : get_highest_combination");
         }
 
-        cards.sort_by(|a,b| a.number.cmp(&b.number));
+        cards.sort_by_key(|card| card.number);
         
         let mut type_map = std::collections::HashMap::new();
         type_map.insert(CardType::Heart, 0);
@@ -1014,8 +2164,8 @@ impl CombinationBuilder {
                 // Check if royal straight flush or straight flush holds
                 // set default suit 
                 let suit : CardType = cards[index].card_type;
-                for i in index + 1..index + COMB_COUNT {
-                    if suit != cards[i].card_type {
+                for card in &cards[index + 1..index + COMB_COUNT] {
+                    if suit != card.card_type {
                         flush_straight = false;
                         break;
                     }
@@ -0,0 +1,364 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use futures::SinkExt;
+use futures::stream::SplitSink;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use warp::Filter;
+use warp::ws::{Message, WebSocket};
+
+use crate::models::{ServerResponse, ResponseType};
+
+// Frames that can wait for a client before slow client policy applies
+const OUTBOX_CAPACITY : usize = 256;
+// Smaller queue would overflow on a single round of game messages
+const MIN_OUTBOX_CAPACITY : usize = 16;
+
+// What happens when client doesn't read as fast as server writes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowClientPolicy {
+    // Drop chat, lobby events and pings
+    Drop,
+    // Also drop game updates and send one snapshot when client catches up
+    Merge,
+    // Close the socket
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxConfig {
+    pub capacity: usize,
+    pub policy: SlowClientPolicy,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            capacity: OUTBOX_CAPACITY,
+            policy: SlowClientPolicy::Merge,
+        }
+    }
+}
+
+impl OutboxConfig {
+    pub fn new(capacity: usize, policy: SlowClientPolicy) -> Self {
+        let clamped = capacity.max(MIN_OUTBOX_CAPACITY);
+        if clamped != capacity {
+            eprintln!("Outbox capacity is clamped to {}", clamped);
+        }
+        Self {
+            capacity: clamped,
+            policy,
+        }
+    }
+}
+
+// How much client needs a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    // Never given up, client is disconnected when these don't fit
+    Critical,
+    // Game progress which a snapshot can replace
+    Update,
+    // Fine to lose
+    Optional,
+}
+
+impl Priority {
+    pub fn of(res: &ServerResponse) -> Self {
+        match res.response_type {
+            ResponseType::Hello |
+            ResponseType::Error |
+            ResponseType::RoomId |
+            ResponseType::Token |
+            ResponseType::InviteCode |
+            ResponseType::Queue |
+            ResponseType::Snapshot |
+            ResponseType::BetResult |
+            ResponseType::RoundResult |
+            ResponseType::GameResult |
+            ResponseType::Series => Priority::Critical,
+            ResponseType::Env |
+            ResponseType::State |
+            ResponseType::Community |
+            ResponseType::Hand |
+            ResponseType::Raise |
+            ResponseType::Deadline |
+            ResponseType::SitOut |
+            ResponseType::Connection |
+            ResponseType::Rematch |
+            ResponseType::Reveal => Priority::Update,
+            ResponseType::Message |
+            ResponseType::Chat |
+            ResponseType::ChatHistory |
+            ResponseType::Rooms |
+            ResponseType::Lobby => Priority::Optional,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    frames: VecDeque<(Priority, Message)>,
+    closed: bool,
+    // Game updates were given up and client needs a snapshot
+    stale: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    policy: SlowClientPolicy,
+    senders: AtomicUsize,
+    // Wakes receiver when frame is queued or outbox is closed
+    queued: Notify,
+    // Wakes socket handler when outbox is closed
+    closed: Notify,
+    metrics: Arc<OutboxMetrics>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn count(&self, counter: fn(&OutboxMetrics) -> &AtomicU64, amount: usize) {
+        counter(&self.metrics).fetch_add(amount as u64, Ordering::Relaxed);
+    }
+
+    fn close(&self, queue: &mut Queue) {
+        queue.closed = true;
+        self.queued.notify();
+        self.closed.notify();
+    }
+}
+
+// Sending half of a bounded outbound queue of a websocket
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+impl Outbox {
+    // Returns false when outbox is closed
+    pub fn send(&self, priority: Priority, msg: Message) -> bool {
+        let shared = &self.shared;
+        let mut queue = shared.lock();
+        if queue.closed {
+            return false;
+        }
+        // Snapshot will be sent instead
+        if queue.stale && priority == Priority::Update {
+            shared.count(|metrics| &metrics.merged, 1);
+            return true;
+        }
+
+        if queue.frames.len() >= shared.capacity {
+            if shared.policy != SlowClientPolicy::Disconnect && priority == Priority::Optional {
+                shared.count(|metrics| &metrics.dropped, 1);
+                return true;
+            }
+
+            let before = queue.frames.len();
+            match shared.policy {
+                SlowClientPolicy::Drop => {
+                    queue.frames.retain(|(priority, _)| *priority != Priority::Optional);
+                    shared.count(|metrics| &metrics.dropped, before - queue.frames.len());
+                }
+                SlowClientPolicy::Merge => {
+                    queue.frames.retain(|(priority, _)| *priority == Priority::Critical);
+                    queue.stale = true;
+                    shared.count(|metrics| &metrics.merged, before - queue.frames.len());
+                    if priority == Priority::Update {
+                        shared.count(|metrics| &metrics.merged, 1);
+                        return true;
+                    }
+                }
+                SlowClientPolicy::Disconnect => {}
+            }
+
+            // Frames that can't be given up still don't fit
+            if queue.frames.len() >= shared.capacity {
+                eprintln!("Disconnected slow client");
+                shared.count(|metrics| &metrics.disconnected, 1);
+                queue.frames.clear();
+                queue.frames.push_back((Priority::Critical, Message::close()));
+                shared.close(&mut queue);
+                return false;
+            }
+        }
+
+        queue.frames.push_back((priority, msg));
+        shared.queued.notify();
+        true
+    }
+
+    // Client has caught up after merged updates and should get a snapshot now.
+    // Flag is cleared so that snapshot is sent only once.
+    pub fn take_stale(&self) -> bool {
+        let mut queue = self.shared.lock();
+        if queue.stale && queue.frames.len() < self.shared.capacity {
+            queue.stale = false;
+            return true;
+        }
+        false
+    }
+
+    // Resolves when outbox has been closed by policy or socket
+    pub async fn closed(&self) {
+        loop {
+            if self.shared.lock().closed {
+                return;
+            }
+            self.shared.closed.notified().await;
+        }
+    }
+}
+
+impl Clone for Outbox {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Outbox {
+    // Receiver ends after queued frames when every sender is gone
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let mut queue = self.shared.lock();
+            self.shared.close(&mut queue);
+        }
+    }
+}
+
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+}
+
+impl OutboxReceiver {
+    // None when outbox is closed and every queued frame is taken
+    pub async fn next(&mut self) -> Option<Message> {
+        loop {
+            {
+                let mut queue = self.shared.lock();
+                if let Some((_, msg)) = queue.frames.pop_front() {
+                    return Some(msg);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.shared.queued.notified().await;
+        }
+    }
+
+}
+
+impl Drop for OutboxReceiver {
+    // Socket is gone, senders stop queueing
+    fn drop(&mut self) {
+        let mut queue = self.shared.lock();
+        queue.frames.clear();
+        self.shared.close(&mut queue);
+    }
+}
+
+#[derive(Default)]
+pub struct OutboxMetrics {
+    outboxes: Mutex<Vec<Weak<Shared>>>,
+    dropped: AtomicU64,
+    merged: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+// Opens outbound queues of websockets and keeps their metrics
+#[derive(Clone, Default)]
+pub struct Outboxes {
+    config: OutboxConfig,
+    metrics: Arc<OutboxMetrics>,
+}
+
+impl Outboxes {
+    pub fn new(config: OutboxConfig) -> Self {
+        Self {
+            config,
+            metrics: Arc::default(),
+        }
+    }
+
+    // Policy of merging needs a client which understands snapshot,
+    // others fall back to dropping.
+    pub fn channel(&self, resync: bool) -> (Outbox, OutboxReceiver) {
+        let policy = match self.config.policy {
+            SlowClientPolicy::Merge if !resync => SlowClientPolicy::Drop,
+            policy => policy,
+        };
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            capacity: self.config.capacity,
+            policy,
+            senders: AtomicUsize::new(1),
+            queued: Notify::new(),
+            closed: Notify::new(),
+            metrics: self.metrics.clone(),
+        });
+        self.metrics.outboxes.lock().unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::downgrade(&shared));
+        (Outbox { shared: shared.clone() }, OutboxReceiver { shared })
+    }
+
+    // Queued frames are written to socket in separate task
+    // which ends when outbox is closed.
+    pub fn open(&self, mut user_tx: SplitSink<WebSocket, Message>, resync: bool) -> Outbox {
+        let (outbox, mut receiver) = self.channel(resync);
+
+        tokio::task::spawn(async move {
+            while let Some(msg) = receiver.next().await {
+                if let Err(e) = user_tx.send(msg).await {
+                    eprintln!("websocket error: {:?}", e);
+                    return;
+                }
+            }
+            let _ = user_tx.close().await;
+        });
+        outbox
+    }
+
+    // Metrics in prometheus text format
+    pub fn metrics(&self) -> String {
+        let (open, queued, max_depth) = {
+            let mut outboxes = self.metrics.outboxes.lock().unwrap_or_else(PoisonError::into_inner);
+            outboxes.retain(|outbox| outbox.strong_count() > 0);
+            let depths = outboxes.iter()
+                .filter_map(|outbox| outbox.upgrade())
+                .map(|outbox| outbox.lock().frames.len())
+                .collect::<Vec<usize>>();
+            (depths.len(), depths.iter().sum::<usize>(), depths.iter().copied().max().unwrap_or(0))
+        };
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        [
+            ("card_outbox_open", "Open outbound queues", open as u64),
+            ("card_outbox_capacity", "Frames each queue holds", self.config.capacity as u64),
+            ("card_outbox_queued_frames", "Frames waiting in every queue", queued as u64),
+            ("card_outbox_max_depth", "Frames waiting in the fullest queue", max_depth as u64),
+            ("card_outbox_dropped_total", "Optional frames dropped for slow clients", counter(&self.metrics.dropped)),
+            ("card_outbox_merged_total", "Game updates replaced by snapshot", counter(&self.metrics.merged)),
+            ("card_outbox_disconnected_total", "Slow clients disconnected", counter(&self.metrics.disconnected)),
+        ].iter()
+            .map(|(name, help, value)| {
+                let kind = if name.ends_with("_total") { "counter" } else { "gauge" };
+                format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value)
+            })
+            .collect()
+    }
+}
+
+pub fn with_outboxes(outboxes: Outboxes) -> impl Filter<Extract = (Outboxes,), Error = Infallible> + Clone {
+    warp::any().map(move || outboxes.clone())
+}
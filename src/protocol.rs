@@ -0,0 +1,271 @@
+use serde::{ Deserialize , Serialize};
+use serde::de::DeserializeOwned;
+use schemars::JsonSchema;
+use warp::ws::Message;
+
+use crate::accounts::Account;
+use crate::models::{GameState, ResponseType, ResponseValue, ServerResponse};
+use crate::outbox::{Outbox, Priority};
+
+// Major version changes break compatibility,
+// minor version only adds new responses and actions.
+pub const PROTOCOL_MAJOR : u32 = 1;
+pub const PROTOCOL_MINOR : u32 = 1;
+
+// Features that server supports, client may declare only some of them
+pub const FEATURES : &[&str] = &[
+    "series",
+    "spectate",
+    "timebank",
+    "sitout",
+    "resume",
+    "resync",
+    "lobby",
+    "invite",
+    "quickplay",
+    "chat",
+    "msgpack",
+    "cbor",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ProtocolVersion {
+    // Clients that don't send version are treated as first protocol
+    pub fn legacy() -> Self {
+        Self {
+            major: 1,
+            minor: 0,
+        }
+    }
+
+    pub fn current() -> Self {
+        Self {
+            major: PROTOCOL_MAJOR,
+            minor: PROTOCOL_MINOR,
+        }
+    }
+
+    // Parse "major.minor"
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut split = version.trim().splitn(2, '.');
+        let major = split.next()?.parse().ok()?;
+        let minor = split.next().map_or(Some(0), |minor| minor.parse().ok())?;
+        Some(Self {
+            major,
+            minor,
+        })
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+// Encoding of websocket frames
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    // Text frames
+    #[default]
+    Json,
+    // Binary frames with named fields
+    Msgpack,
+    Cbor,
+}
+
+impl WireFormat {
+    pub fn encode<T: Serialize>(&self, value: &T) -> Message {
+        match self {
+            WireFormat::Json => Message::text(serde_json::to_string(value).expect("Failed to encode json")),
+            WireFormat::Msgpack => Message::binary(rmp_serde::to_vec_named(value).expect("Failed to encode msgpack")),
+            WireFormat::Cbor => {
+                let mut bytes = vec![];
+                ciborium::ser::into_writer(value, &mut bytes).expect("Failed to encode cbor");
+                Message::binary(bytes)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Option<T> {
+        match self {
+            WireFormat::Json => None,
+            WireFormat::Msgpack => rmp_serde::from_slice(bytes).ok(),
+            WireFormat::Cbor => ciborium::de::from_reader(bytes).ok(),
+        }
+    }
+}
+
+// Hello sent by client as query of websocket routes
+// e.g. /create?version=1.1&capabilities=chat,resume&format=msgpack
+#[derive(Deserialize, Debug, Default)]
+pub struct ClientHello {
+    pub version: Option<String>,
+    // Comma separated feature names
+    pub capabilities: Option<String>,
+    pub format: Option<WireFormat>,
+    // Token from login, client plays as guest without it
+    pub auth: Option<String>,
+    // Filled by route after auth token is verified
+    #[serde(skip)]
+    pub account: Option<Result<Account, String>>,
+}
+
+impl ClientHello {
+    // Format is known even when client is rejected
+    pub fn format(&self) -> WireFormat {
+        self.format.unwrap_or_default()
+    }
+
+    // Returns error message for incompatible client
+    pub fn client_info(&self) -> Result<ClientInfo, String> {
+        let version = match &self.version {
+            Some(version) => ProtocolVersion::parse(version)
+                .ok_or_else(|| format!("Invalid protocol version : {}", version))?,
+            None => ProtocolVersion::legacy(),
+        };
+
+        if version.major != PROTOCOL_MAJOR {
+            return Err(format!(
+                "Unsupported protocol version {}, server speaks {}",
+                version, ProtocolVersion::current()
+            ));
+        }
+
+        let capabilities = self.capabilities.as_ref().map(|capabilities| {
+            capabilities.split(',')
+                .map(|feature| feature.trim().to_string())
+                .filter(|feature| !feature.is_empty())
+                .collect::<Vec<String>>()
+        });
+
+        let account = match (&self.auth, &self.account) {
+            (_, Some(account)) => Some(account.clone()?),
+            (Some(_), None) => return Err("Auth token was not verified".to_string()),
+            (None, None) => None,
+        };
+
+        Ok(ClientInfo {
+            version,
+            capabilities,
+            format: self.format(),
+            account,
+        })
+    }
+}
+
+// Reply to client hello
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+pub struct ServerHello {
+    pub version: ProtocolVersion,
+    pub features: Vec<String>,
+}
+
+impl ServerHello {
+    pub fn new() -> Self {
+        Self {
+            version: ProtocolVersion::current(),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub version: ProtocolVersion,
+    // None means every feature of client's version
+    pub capabilities: Option<Vec<String>>,
+    pub format: WireFormat,
+    pub account: Option<Account>,
+}
+
+impl ClientInfo {
+    pub fn encode<T: Serialize>(&self, value: &T) -> Message {
+        self.format.encode(value)
+    }
+
+    // Text frame is always json regardless of format
+    pub fn decode<T: DeserializeOwned>(&self, msg: &Message) -> Option<T> {
+        if let Ok(text) = msg.to_str() {
+            return serde_json::from_str(text).ok();
+        }
+        if !msg.is_binary() {
+            return None;
+        }
+
+        self.format.decode(msg.as_bytes())
+    }
+
+    // Encode and send response unless client doesn't accept it
+    pub fn send(&self, sender: &Outbox, res: &ServerResponse) {
+        if !self.accepts(res) {
+            return;
+        }
+        sender.send(Priority::of(res), self.encode(res));
+    }
+
+    // Compatibility for older clients.
+    // Responses that client doesn't know are not sent
+    // because unknown enum variant fails to be parsed on client.
+    pub fn accepts(&self, res: &ServerResponse) -> bool {
+        let (since, feature) = response_since(res);
+        self.knows(since, feature)
+    }
+
+    // Every feature was added in 1.1
+    pub fn supports(&self, feature: &str) -> bool {
+        self.knows(1, Some(feature))
+    }
+
+    fn knows(&self, since: u32, feature: Option<&str>) -> bool {
+        if self.version.minor < since {
+            return false;
+        }
+
+        match (&self.capabilities, feature) {
+            (Some(capabilities), Some(feature)) => capabilities.iter().any(|cap| cap == feature),
+            _ => true,
+        }
+    }
+}
+
+// Minor version where response was added and feature it belongs to
+fn response_since(res: &ServerResponse) -> (u32, Option<&'static str>) {
+    // Newer values carried by older response types
+    match &res.value {
+        ResponseValue::State((GameState::Lobby, _)) => return (1, Some("series")),
+        ResponseValue::SpectatorBet(_) => return (1, Some("spectate")),
+        _ => {}
+    }
+
+    match res.response_type {
+        ResponseType::Env |
+        ResponseType::State |
+        ResponseType::Community |
+        ResponseType::Hand |
+        ResponseType::Message |
+        ResponseType::Error |
+        ResponseType::RoomId |
+        ResponseType::Raise |
+        ResponseType::BetResult |
+        ResponseType::RoundResult |
+        ResponseType::GameResult => (0, None),
+        ResponseType::Hello => (1, None),
+        ResponseType::Series | ResponseType::Rematch => (1, Some("series")),
+        ResponseType::Reveal => (1, Some("spectate")),
+        ResponseType::Deadline => (1, Some("timebank")),
+        ResponseType::SitOut => (1, Some("sitout")),
+        ResponseType::Token | ResponseType::Connection => (1, Some("resume")),
+        ResponseType::Snapshot => (1, Some("resync")),
+        ResponseType::Rooms | ResponseType::Lobby => (1, Some("lobby")),
+        ResponseType::InviteCode => (1, Some("invite")),
+        ResponseType::Queue => (1, Some("quickplay")),
+        ResponseType::Chat | ResponseType::ChatHistory => (1, Some("chat")),
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use futures::StreamExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{delay_queue, DelayQueue};
+
+use crate::handlers::{Lobby, publish_lobby_event};
+use crate::storage::{MatchRecord, Storage};
+use crate::models::{new_invite_code, Connection, LobbyEvent, RoomAccess, RoomInfo, Rules, Timers, UserRequest, ServerResponse, ResponseType, ResponseValue};
+
+// Open rooms that server holds at most
+const MAX_ROOMS : usize = 1000;
+
+// Rooms which are open, each room is owned by its own task.
+// Registry only keeps handles and what listing and lookup need,
+// so lock is never held while a game is played.
+#[derive(Clone, Default)]
+pub struct Rooms {
+    entries: Arc<RwLock<HashMap<String, RoomEntry>>>,
+    config: RoomConfig,
+    // Finished games are not recorded without storage
+    storage: Option<Storage>,
+}
+
+// How new rooms are set up
+#[derive(Debug, Clone, Copy)]
+pub struct RoomConfig {
+    pub max_rooms: usize,
+    // Used for options that client doesn't give
+    pub rules: Rules,
+    pub timers: Timers,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            max_rooms: MAX_ROOMS,
+            rules: Rules::default(),
+            timers: Timers::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RoomEntry {
+    pub handle: RoomHandle,
+    pub invite_code: String,
+    pub access: RoomAccess,
+    // Reported by room task whenever it changes
+    pub info: RoomInfo,
+    tokens: Vec<String>,
+}
+
+impl Rooms {
+    pub fn new(config: RoomConfig, storage: Storage) -> Self {
+        Self {
+            entries: Arc::default(),
+            config,
+            storage: Some(storage),
+        }
+    }
+
+    pub fn config(&self) -> RoomConfig {
+        self.config
+    }
+
+    // New room is refused when limit is reached
+    pub fn is_full(&self) -> bool {
+        self.read().len() >= self.config.max_rooms
+    }
+
+    // Spawn task of the room and register it.
+    // Invite code is made unique among open rooms.
+    pub fn open(&self, mut connection: Connection, commands: mpsc::UnboundedReceiver<RoomCommand>, lobby: &Lobby) -> RoomHandle {
+        let (sender, inbox) = mpsc::unbounded_channel();
+        let handle = RoomHandle { sender };
+        {
+            let mut entries = self.write();
+            while entries.values().any(|entry| entry.invite_code == connection.invite_code) {
+                connection.invite_code = new_invite_code();
+            }
+            entries.insert(connection.room_id.clone(), RoomEntry {
+                handle: handle.clone(),
+                invite_code: connection.invite_code.clone(),
+                access: connection.access.clone(),
+                info: connection.info(),
+                tokens: connection.game.tokens(),
+            });
+        }
+        publish_lobby_event(lobby, &connection, LobbyEvent::RoomCreated(connection.info()));
+
+        let task = RoomTask {
+            connection,
+            rooms: self.clone(),
+            lobby: lobby.clone(),
+            timers: RoomTimers::default(),
+            closed: false,
+        };
+        tokio::task::spawn(task.run(inbox, commands));
+        handle
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<RoomHandle> {
+        self.read().get(room_id).map(|entry| entry.handle.clone())
+    }
+
+    // Room is found either with room id or invite code
+    pub fn find(&self, room_key: &str) -> Option<RoomEntry> {
+        let entries = self.read();
+        entries.get(room_key)
+            .or_else(|| entries.values().find(|entry| entry.invite_code.eq_ignore_ascii_case(room_key)))
+            .cloned()
+    }
+
+    // Room which has a player with given resume token
+    pub fn find_token(&self, token: &str) -> Option<RoomHandle> {
+        self.read().values()
+            .find(|entry| entry.tokens.iter().any(|other| other == token))
+            .map(|entry| entry.handle.clone())
+    }
+
+    // Private or password protected rooms are not listed
+    pub fn listed(&self) -> Vec<RoomInfo> {
+        self.read().values()
+            .filter(|entry| entry.access.is_listed())
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    fn report(&self, room_id: &str, info: RoomInfo, tokens: Vec<String>) {
+        if let Some(entry) = self.write().get_mut(room_id) {
+            entry.info = info;
+            entry.tokens = tokens;
+        }
+    }
+
+    fn remove(&self, room_id: &str) {
+        self.write().remove(room_id);
+    }
+
+    // Nothing panics while registry is locked, but a poisoned lock
+    // must not take down every other room either.
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<String, RoomEntry>> {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, RoomEntry>> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Messages that room task receives from outside
+pub enum RoomMessage {
+    // Action of a seat from given session
+    Action { user_id: String, session: u32, req: UserRequest },
+    // Socket of a seat has been closed
+    Disconnect { user_id: String, session: u32 },
+    // Run function on the room, used for seating, resuming and spectating
+    Call(Box<dyn FnOnce(&mut Connection) + Send>),
+}
+
+#[derive(Clone)]
+pub struct RoomHandle {
+    sender: mpsc::UnboundedSender<RoomMessage>,
+}
+
+impl RoomHandle {
+    // False when room has been closed
+    pub fn send(&self, message: RoomMessage) -> bool {
+        self.sender.send(message).is_ok()
+    }
+
+    // Run function in room task and wait for the result.
+    // None when room has been closed.
+    pub async fn call<R, F>(&self, function: F) -> Option<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> R + Send + 'static,
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+        let sent = self.send(RoomMessage::Call(Box::new(move |connection| {
+            let _ = result_tx.send(function(connection));
+        })));
+        if !sent {
+            return None;
+        }
+        result_rx.await.ok()
+    }
+}
+
+// Task which owns the room. Messages, commands and timers
+// are processed one at a time, thus game needs no lock.
+struct RoomTask {
+    connection: Connection,
+    rooms: Rooms,
+    lobby: Lobby,
+    timers: RoomTimers,
+    closed: bool,
+}
+
+impl RoomTask {
+    async fn run(mut self, mut inbox: mpsc::UnboundedReceiver<RoomMessage>, mut commands: mpsc::UnboundedReceiver<RoomCommand>) {
+        let mut reported = (self.connection.info(), self.connection.game.tokens());
+        loop {
+            // Commands that game has sent while handling last message are applied first,
+            // so timers of previous states are cancelled before they could fire.
+            while let Ok(command) = commands.try_recv() {
+                self.command(command);
+            }
+            if self.closed {
+                break;
+            }
+
+            self.connection.game.resync_stale();
+            let current = (self.connection.info(), self.connection.game.tokens());
+            if current != reported {
+                self.rooms.report(&self.connection.room_id, current.0.clone(), current.1.clone());
+                reported = current;
+            }
+
+            tokio::select! {
+                message = inbox.next() => match message {
+                    Some(message) => self.receive(message),
+                    None => break,
+                },
+                timer = self.timers.next() => self.expire(timer),
+            }
+        }
+    }
+
+    fn command(&mut self, command: RoomCommand) {
+        match command {
+            RoomCommand::Timer { state_id, duration } => self.timers.start_state(state_id, duration),
+            RoomCommand::GraceTimer { user_id, session, duration } => self.timers.start_grace(user_id, session, duration),
+            // Pending timers are dropped together with the task
+            RoomCommand::GameEnd => self.close(),
+            RoomCommand::Record(record) => self.record(record),
+        }
+    }
+
+    // Writing to database blocks, thus it's done on its own thread
+    fn record(&self, record: MatchRecord) {
+        let storage = match self.rooms.storage.clone() {
+            Some(storage) => storage,
+            None => return,
+        };
+        let room_id = self.connection.room_id.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = storage.record_match(&room_id, &record) {
+                eprintln!("Couldn't record match of room {} : {}", room_id, err);
+            }
+        });
+    }
+
+    fn receive(&mut self, message: RoomMessage) {
+        let game = &mut self.connection.game;
+        match message {
+            RoomMessage::Action { user_id, session, req } => {
+                // Old socket of resumed user can't act on the seat
+                if !game.is_current_session(&user_id, session) {
+                    eprintln!("Dropped action from outdated session");
+                    return;
+                }
+                let pending = game.receive_player_action(&user_id, req);
+                game.pending_next_state(pending);
+            }
+            RoomMessage::Disconnect { user_id, session } => {
+                // Nobody else is in the room, so simply remove the room
+                if game.participant.is_none() {
+                    eprintln!("User disconnected from room : {}", self.connection.room_id);
+                    self.close();
+                    return;
+                }
+
+                // Keep the room so that user can resume the game
+                // with token in grace period. Opponent is notified by the game.
+                if game.disconnect_user(&user_id, session) {
+                    eprintln!("User disconnected from room : {}", self.connection.room_id);
+                    publish_lobby_event(&self.lobby, &self.connection, LobbyEvent::PlayerLeft(self.connection.info()));
+                }
+            }
+            RoomMessage::Call(function) => function(&mut self.connection),
+        }
+    }
+
+    fn expire(&mut self, timer: RoomTimer) {
+        match timer {
+            // Timers of previous states are already cancelled
+            RoomTimer::State(_) => self.connection.game.next_state(),
+            RoomTimer::Grace { user_id, session } => {
+                if !self.connection.game.is_grace_expired(&user_id, session) {
+                    return;
+                }
+
+                // User didn't come back, close the room
+                eprintln!("Closed room after grace period : {}", self.connection.room_id);
+                self.close();
+                if let Some(opponent) = self.connection.game.opponent_of(&user_id) {
+                    opponent.send_message(
+                        &ServerResponse::new(
+                            ResponseType::Error, 
+                            ResponseValue::Message("Opponent player disconnected".to_string())
+                        ));
+                }
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.rooms.remove(&self.connection.room_id);
+        publish_lobby_event(&self.lobby, &self.connection, LobbyEvent::RoomClosed(self.connection.room_id.clone()));
+    }
+}
+
+// Commands that game sends to its room
+#[derive(Debug)]
+pub enum RoomCommand {
+    // Move to next state after duration.
+    // Timers of previous states are cancelled when timer of a new state starts.
+    Timer { state_id: String, duration: Duration },
+    // Close the room unless user has come back in the session
+    GraceTimer { user_id: String, session: u32, duration: Duration },
+    // Game is over and room should be closed
+    GameEnd,
+    // Finished game to be stored in match history
+    Record(MatchRecord),
+}
+
+// Timer which has expired
+#[derive(Debug, PartialEq)]
+pub enum RoomTimer {
+    State(String),
+    Grace { user_id: String, session: u32 },
+}
+
+// Running timers of a room
+#[derive(Default)]
+pub struct RoomTimers {
+    queue: DelayQueue<(u64, RoomTimer)>,
+    // Keys of running state timers with their state id
+    state_keys: HashMap<u64, (String, delay_queue::Key)>,
+    next_id: u64,
+}
+
+impl RoomTimers {
+    pub fn start_state(&mut self, state_id: String, duration: Duration) {
+        self.cancel_other_states(&state_id);
+        let id = self.new_id();
+        let key = self.queue.insert((id, RoomTimer::State(state_id.clone())), duration);
+        self.state_keys.insert(id, (state_id, key));
+    }
+
+    pub fn start_grace(&mut self, user_id: String, session: u32, duration: Duration) {
+        let id = self.new_id();
+        self.queue.insert((id, RoomTimer::Grace { user_id, session }), duration);
+    }
+
+    // Betting state has a timer for each player, thus
+    // every timer of the same state is kept.
+    fn cancel_other_states(&mut self, state_id: &str) {
+        let queue = &mut self.queue;
+        self.state_keys.retain(|_, (id, key)| {
+            if id == state_id {
+                return true;
+            }
+            queue.remove(key);
+            false
+        });
+    }
+
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // Next expired timer, never resolves while there is no timer
+    pub async fn next(&mut self) -> RoomTimer {
+        loop {
+            if self.queue.is_empty() {
+                futures::future::pending::<()>().await;
+            }
+
+            match self.queue.next().await {
+                Some(Ok(expired)) => {
+                    let (id, timer) = expired.into_inner();
+                    self.state_keys.remove(&id);
+                    return timer;
+                }
+                Some(Err(err)) => eprintln!("Room timer error : {}", err),
+                None => {}
+            }
+        }
+    }
+}
@@ -1,18 +1,146 @@
 use warp::Filter;
 
 use crate::handlers::*;
+use crate::matchmaking::{Queue, quickplay_handler, with_queue};
+use crate::heartbeat::{HeartbeatConfig, with_heartbeat};
+use crate::room::Rooms;
+use crate::outbox::{Outboxes, with_outboxes};
+use crate::accounts::{Accounts, client_hello, login_handler, register_handler, with_accounts};
+use crate::storage::{MatchesQuery, Storage, matches_handler, profile_handler, with_storage};
+use crate::models::{CreateOption, JoinOption, QuickplayOption, RoomFilter, WatchOption};
 
-pub fn create_room(conn: &Connections) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+// Bytes of register and login body
+const CREDENTIALS_LIMIT : u64 = 4 * 1024;
+
+pub fn create_room(rooms: &Rooms, lobby: &Lobby, heartbeat: HeartbeatConfig, outboxes: &Outboxes, accounts: &Accounts) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("create")
         .and(warp::ws())
-        .and(with_conns(conn.clone()))
+        .and(warp::query::<CreateOption>())
+        .and(client_hello(accounts.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_heartbeat(heartbeat))
+        .and(with_outboxes(outboxes.clone()))
         .and_then(create_handler)
 }
 
-pub fn join_room(conn: &Connections) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn get_rooms(rooms: &Rooms) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("rooms")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<RoomFilter>())
+        .and(with_rooms(rooms.clone()))
+        .and_then(rooms_handler)
+}
+
+pub fn join_room(rooms: &Rooms, lobby: &Lobby, heartbeat: HeartbeatConfig, outboxes: &Outboxes, accounts: &Accounts) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("join")
         .and(warp::ws())
         .and(warp::path::param())
-        .and(with_conns(conn.clone()))
+        .and(warp::query::<JoinOption>())
+        .and(client_hello(accounts.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_heartbeat(heartbeat))
+        .and(with_outboxes(outboxes.clone()))
         .and_then(join_handler)
 }
+
+pub fn watch_lobby(rooms: &Rooms, lobby: &Lobby, heartbeat: HeartbeatConfig, outboxes: &Outboxes, accounts: &Accounts) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("lobby")
+        .and(warp::ws())
+        .and(client_hello(accounts.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_heartbeat(heartbeat))
+        .and(with_outboxes(outboxes.clone()))
+        .and_then(lobby_handler)
+}
+
+pub fn watch_room(rooms: &Rooms, heartbeat: HeartbeatConfig, outboxes: &Outboxes, accounts: &Accounts) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("watch")
+        .and(warp::ws())
+        .and(warp::path::param())
+        .and(warp::query::<WatchOption>())
+        .and(client_hello(accounts.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_heartbeat(heartbeat))
+        .and(with_outboxes(outboxes.clone()))
+        .and_then(watch_handler)
+}
+
+pub fn resume_game(rooms: &Rooms, lobby: &Lobby, heartbeat: HeartbeatConfig, outboxes: &Outboxes, accounts: &Accounts) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("resume")
+        .and(warp::ws())
+        .and(warp::path::param())
+        .and(client_hello(accounts.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_heartbeat(heartbeat))
+        .and(with_outboxes(outboxes.clone()))
+        .and_then(resume_handler)
+}
+
+pub fn quickplay(rooms: &Rooms, lobby: &Lobby, queue: &Queue, heartbeat: HeartbeatConfig, outboxes: &Outboxes, accounts: &Accounts) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("quickplay")
+        .and(warp::ws())
+        .and(warp::query::<QuickplayOption>())
+        .and(client_hello(accounts.clone()))
+        .and(with_rooms(rooms.clone()))
+        .and(with_lobby(lobby.clone()))
+        .and(with_queue(queue.clone()))
+        .and(with_heartbeat(heartbeat))
+        .and(with_outboxes(outboxes.clone()))
+        .and_then(quickplay_handler)
+}
+
+// Route is not found unless metrics are enabled in config
+pub fn get_metrics(outboxes: &Outboxes, enabled: bool) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().and_then(move || async move {
+            if enabled { Ok(()) } else { Err(warp::reject::not_found()) }
+        }))
+        .untuple_one()
+        .and(with_outboxes(outboxes.clone()))
+        .and_then(metrics_handler)
+}
+
+pub fn register(accounts: &Accounts) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("register")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(CREDENTIALS_LIMIT))
+        .and(warp::body::json())
+        .and(with_accounts(accounts.clone()))
+        .and_then(register_handler)
+}
+
+pub fn login(accounts: &Accounts) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("login")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(CREDENTIALS_LIMIT))
+        .and(warp::body::json())
+        .and(with_accounts(accounts.clone()))
+        .and_then(login_handler)
+}
+
+pub fn get_profile(storage: &Storage) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("players")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(with_storage(storage.clone()))
+        .and_then(profile_handler)
+}
+
+pub fn get_matches(storage: &Storage) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("players")
+        .and(warp::get())
+        .and(warp::path::param())
+        .and(warp::path("matches"))
+        .and(warp::path::end())
+        .and(warp::query::<MatchesQuery>())
+        .and(with_storage(storage.clone()))
+        .and_then(matches_handler)
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+use crate::accounts::{Credentials, Session};
+use crate::agent::{PlayerAgent, ReplayAgent, play_seats};
+use crate::models::{Card, CardType, ChatMessage, Emote, Game, GameMode, LobbyEvent, PlayerAction, QueueAction, QueueRequest, Reveal, RoomInfo, Rules, Seat, Timers, SeriesScore, SitOutStatus, ServerResponse, ResponseType, ResponseValue, UserRequest};
+use crate::protocol::ServerHello;
+use crate::storage::{MatchSummary, Profile};
+
+// Schema of every message exchanged with clients.
+// Client models are generated from exported files
+// and CI diffs them against the committed schema directory.
+pub fn schemas() -> BTreeMap<&'static str, RootSchema> {
+    let mut schemas = BTreeMap::new();
+    schemas.insert("server_response", schema_for!(ServerResponse));
+    schemas.insert("user_request", schema_for!(UserRequest));
+    schemas.insert("queue_request", schema_for!(QueueRequest));
+    schemas.insert("room_info", schema_for!(Vec<RoomInfo>));
+    schemas.insert("credentials", schema_for!(Credentials));
+    schemas.insert("session", schema_for!(Session));
+    schemas.insert("profile", schema_for!(Profile));
+    schemas.insert("matches", schema_for!(Vec<MatchSummary>));
+    schemas
+}
+
+// Write schema and samples into given directory
+pub fn export(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (name, schema) in schemas() {
+        let json = serde_json::to_string_pretty(&schema)?;
+        std::fs::write(dir.join(format!("{}.json", name)), json + "\n")?;
+    }
+
+    let samples = serde_json::to_string_pretty(&samples())?;
+    std::fs::write(dir.join("samples.json"), samples + "\n")?;
+    Ok(())
+}
+
+// Sample payloads keyed by message type.
+// Game events are taken from a scripted game
+// so that samples are exactly what server sends.
+pub fn samples() -> BTreeMap<String, Value> {
+    let mut responses = BTreeMap::new();
+    let mut add = |res: ServerResponse| {
+        let key = format!("{:?}", res.response_type);
+        responses.entry(key).or_insert_with(|| serde_json::to_value(&res).expect("Failed to serialize sample"));
+    };
+
+    for res in scripted_game() {
+        add(res);
+    }
+
+    let cards = vec![Card::new(CardType::Spade, 1), Card::new(CardType::Heart, 13)];
+    let room = RoomInfo {
+        room_id: "0f8fad5bd9cb469fa16570867728950e".to_string(),
+        invite_code: "K7QX2M".to_string(),
+        rules: Rules::default(),
+        seats_taken: 1,
+        in_progress: false,
+    };
+    let manual = vec![
+        (ResponseType::RoomId, ResponseValue::Message(room.room_id.clone())),
+        (ResponseType::InviteCode, ResponseValue::Message(room.invite_code.clone())),
+        (ResponseType::Token, ResponseValue::Message("7c9e6679742540de944be07fc1f90ae7".to_string())),
+        (ResponseType::Error, ResponseValue::Message("There is no such room with given id.".to_string())),
+        (ResponseType::Message, ResponseValue::Message(format!("Successfully joined a room : {}", room.room_id))),
+        (ResponseType::Queue, ResponseValue::Message("Waiting for opponent".to_string())),
+        (ResponseType::Rematch, ResponseValue::Message("Opponent wants a rematch".to_string())),
+        (ResponseType::Hello, ResponseValue::Hello(ServerHello::new())),
+        (ResponseType::Rooms, ResponseValue::Rooms(vec![room.clone()])),
+        (ResponseType::Lobby, ResponseValue::Lobby(LobbyEvent::RoomCreated(room))),
+        (ResponseType::GameResult, ResponseValue::GameResult(true)),
+        (ResponseType::ChatHistory, ResponseValue::ChatHistory(vec![ChatMessage {
+            seat: Seat::Creator,
+            text: Some("Hello".to_string()),
+            emote: None,
+            time: 1_600_000_000_000,
+        }])),
+        (ResponseType::Series, ResponseValue::Series(SeriesScore { wins: 1, opp_wins: 0, best_of: 3, decided: false })),
+        (ResponseType::SitOut, ResponseValue::SitOut(SitOutStatus { sitting_out: false, opp_sitting_out: true })),
+        (ResponseType::Reveal, ResponseValue::Reveal(Reveal { creator_cards: cards.clone(), participant_cards: cards })),
+    ];
+    for (response_type, value) in manual {
+        add(ServerResponse::new(response_type, value));
+    }
+
+    let mut samples = BTreeMap::new();
+    samples.insert("ServerResponse".to_string(), serde_json::to_value(responses).unwrap());
+    samples.insert("UserRequest".to_string(), serde_json::json!([
+        UserRequest {
+            state_id: "5d2f6b3b2c6a4d8f9b1e7a0c3e4f5a6b".to_string(),
+            action: PlayerAction::Raise,
+            value: Some(1),
+            text: None,
+            emote: None,
+        },
+        UserRequest {
+            state_id: String::new(),
+            action: PlayerAction::Message,
+            value: None,
+            text: Some("Good game".to_string()),
+            emote: Some(Emote::WellPlayed),
+        },
+    ]));
+    samples.insert("QueueRequest".to_string(), serde_json::to_value(QueueRequest { action: QueueAction::Cancel }).unwrap());
+    samples.insert("GameMode".to_string(), serde_json::to_value(GameMode::Single).unwrap());
+    samples
+}
+
+// Replay a round with scripted seats and collect events sent to creator
+fn scripted_game() -> Vec<ServerResponse> {
+    let (creator_tx, mut creator_rx) = mpsc::unbounded_channel();
+    let (part_tx, _part_rx) = mpsc::unbounded_channel();
+    let (commands_tx, _commands_rx) = mpsc::unbounded_channel();
+
+    // Participant raises on flop, then both check to showdown
+    let mut creator = ReplayAgent::new(vec![
+        (PlayerAction::Check, Some(0)),
+        (PlayerAction::Call, Some(1)),
+        (PlayerAction::Check, Some(0)),
+        (PlayerAction::Check, Some(0)),
+    ], creator_tx);
+    let mut participant = ReplayAgent::new(vec![
+        (PlayerAction::Raise, Some(1)),
+        (PlayerAction::Check, Some(0)),
+        (PlayerAction::Check, Some(0)),
+    ], part_tx);
+    let mut seats = vec![
+        ("creator".to_string(), creator.take_actions().unwrap()),
+        ("participant".to_string(), participant.take_actions().unwrap()),
+    ];
+
+    let mut game = Game::new("creator".to_string(), Box::new(creator), commands_tx, Rules::default(), Timers::default());
+    game.join_game("participant".to_string(), Box::new(participant));
+
+    let chat = UserRequest {
+        state_id: String::new(),
+        action: PlayerAction::Message,
+        value: None,
+        text: Some("Good luck".to_string()),
+        emote: None,
+    };
+    game.receive_player_action("participant", chat);
+
+    game.init_game();
+    play_seats(&mut game, &mut seats);
+
+    let resync = UserRequest {
+        state_id: String::new(),
+        action: PlayerAction::Resync,
+        value: None,
+        text: None,
+        emote: None,
+    };
+    game.receive_player_action("creator", resync);
+    game.disconnect_user("participant", 0);
+
+    let mut events = vec![];
+    while let Ok(res) = creator_rx.try_recv() {
+        events.push(res);
+    }
+    events
+}
@@ -0,0 +1,415 @@
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Row};
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::accounts::{Account, AccountStore, AuthError};
+use crate::models::{now_millis, CardCombination, GameMode, GUEST_NAME};
+
+// Matches that history route returns when client doesn't ask
+const DEFAULT_MATCHES : u32 = 10;
+const MAX_MATCHES : u32 = 50;
+
+// Tables are created when missing, thus a new file is ready to use
+const SCHEMA : &str = "
+    CREATE TABLE IF NOT EXISTS players (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        name_key TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        created INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS matches (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        room_id TEXT NOT NULL,
+        game INTEGER NOT NULL,
+        mode TEXT NOT NULL,
+        stakes INTEGER NOT NULL,
+        creator_id TEXT,
+        creator_name TEXT NOT NULL,
+        participant_id TEXT,
+        participant_name TEXT NOT NULL,
+        creator_won INTEGER NOT NULL,
+        creator_hp INTEGER NOT NULL,
+        participant_hp INTEGER NOT NULL,
+        finished INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS matches_creator ON matches (creator_id, finished);
+    CREATE INDEX IF NOT EXISTS matches_participant ON matches (participant_id, finished);
+    CREATE TABLE IF NOT EXISTS hands (
+        match_id INTEGER NOT NULL REFERENCES matches (id) ON DELETE CASCADE,
+        number INTEGER NOT NULL,
+        creator_won INTEGER,
+        creator_fold INTEGER NOT NULL,
+        participant_fold INTEGER NOT NULL,
+        creator_comb TEXT,
+        participant_comb TEXT,
+        pot INTEGER NOT NULL,
+        creator_hp INTEGER NOT NULL,
+        participant_hp INTEGER NOT NULL,
+        PRIMARY KEY (match_id, number)
+    );
+";
+
+// Result of a hand as game saw it at showdown, from creator's side
+#[derive(Debug, Clone)]
+pub struct HandRecord {
+    // Starts from 1 in each game
+    pub number: u32,
+    // None when it's a draw
+    pub creator_won: Option<bool>,
+    pub creator_fold: bool,
+    pub participant_fold: bool,
+    // Folded hand is not revealed
+    pub creator_comb: Option<CardCombination>,
+    pub participant_comb: Option<CardCombination>,
+    // Damage that loser of the hand has taken
+    pub pot: u32,
+    // Hp after the hand
+    pub creator_hp: u32,
+    pub participant_hp: u32,
+}
+
+// Game which is over, sent by game to its room to be stored
+#[derive(Debug, Clone)]
+pub struct MatchRecord {
+    // Number of game in the series, starts from 1
+    pub game: u32,
+    pub mode: GameMode,
+    pub stakes: u32,
+    // Guests have no account
+    pub creator: Option<Account>,
+    pub participant: Option<Account>,
+    pub creator_won: bool,
+    pub creator_hp: u32,
+    pub participant_hp: u32,
+    pub hands: Vec<HandRecord>,
+    // Milliseconds since unix epoch
+    pub finished: u64,
+}
+
+// Totals of a registered player
+#[derive(Serialize, Deserialize, Debug, PartialEq, JsonSchema)]
+pub struct Profile {
+    pub account: Account,
+    // Milliseconds since unix epoch
+    pub created: u64,
+    pub matches: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub hands: u32,
+    pub hands_won: u32,
+    pub hands_lost: u32,
+}
+
+// Finished game from the side of player who asked for it
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct MatchSummary {
+    pub id: i64,
+    pub room_id: String,
+    pub game: u32,
+    pub mode: GameMode,
+    pub stakes: u32,
+    pub opp_name: String,
+    // None when opponent was a guest
+    pub opp_id: Option<String>,
+    pub win: bool,
+    pub hp: u32,
+    pub opp_hp: u32,
+    pub hands: Vec<HandSummary>,
+    pub finished: u64,
+}
+
+// Same fields as round result that player got for the hand
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct HandSummary {
+    pub number: u32,
+    pub win: Option<bool>,
+    pub fold: bool,
+    pub opp_fold: bool,
+    pub comb: Option<CardCombination>,
+    pub opp_comb: Option<CardCombination>,
+    pub pot: u32,
+    pub hp: u32,
+    pub opp_hp: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MatchesQuery {
+    pub limit: Option<u32>,
+}
+
+// Players and match history in a SQLite database.
+// Connection is shared, so calls block and should be made off the async runtime.
+#[derive(Clone)]
+pub struct Storage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Storage {
+    // Path ":memory:" keeps everything in memory until server stops
+    pub fn open(path: &str) -> Result<Self, String> {
+        let conn = Connection::open(path)
+            .map_err(|err| format!("Couldn't open database {} : {}", path, err))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .and_then(|_| conn.execute_batch(SCHEMA))
+            .map_err(|err| format!("Couldn't create tables in {} : {}", path, err))?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Game and its hands are stored together or not at all
+    pub fn record_match(&self, room_id: &str, record: &MatchRecord) -> Result<i64, String> {
+        let mut conn = self.lock();
+        let tx = conn.transaction().map_err(|err| err.to_string())?;
+        let name = |account: &Option<Account>| {
+            account.as_ref().map_or(GUEST_NAME.to_string(), |account| account.name.clone())
+        };
+        tx.execute(
+            "INSERT INTO matches (room_id, game, mode, stakes, creator_id, creator_name, participant_id, participant_name,
+                creator_won, creator_hp, participant_hp, finished)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                room_id,
+                record.game,
+                to_text(&record.mode),
+                record.stakes,
+                record.creator.as_ref().map(|account| &account.id),
+                name(&record.creator),
+                record.participant.as_ref().map(|account| &account.id),
+                name(&record.participant),
+                record.creator_won,
+                record.creator_hp,
+                record.participant_hp,
+                record.finished as i64,
+            ],
+        ).map_err(|err| err.to_string())?;
+        let match_id = tx.last_insert_rowid();
+
+        for hand in record.hands.iter() {
+            tx.execute(
+                "INSERT INTO hands (match_id, number, creator_won, creator_fold, participant_fold,
+                    creator_comb, participant_comb, pot, creator_hp, participant_hp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    match_id,
+                    hand.number,
+                    hand.creator_won,
+                    hand.creator_fold,
+                    hand.participant_fold,
+                    hand.creator_comb.as_ref().map(to_text),
+                    hand.participant_comb.as_ref().map(to_text),
+                    hand.pot,
+                    hand.creator_hp,
+                    hand.participant_hp,
+                ],
+            ).map_err(|err| err.to_string())?;
+        }
+        tx.commit().map_err(|err| err.to_string())?;
+        Ok(match_id)
+    }
+
+    // None when there is no such player
+    pub fn profile(&self, player_id: &str) -> Result<Option<Profile>, String> {
+        let conn = self.lock();
+        let player = conn.query_row(
+            "SELECT id, name, created FROM players WHERE id = ?1",
+            params![player_id],
+            |row| Ok((Account { id: row.get(0)?, name: row.get(1)? }, row.get::<_, i64>(2)?)),
+        ).optional().map_err(|err| err.to_string())?;
+        let (account, created) = match player {
+            Some(player) => player,
+            None => return Ok(None),
+        };
+
+        let (matches, wins) : (u32, u32) = conn.query_row(
+            "SELECT COUNT(*),
+                COALESCE(SUM((creator_id = ?1 AND creator_won) OR (participant_id = ?1 AND NOT creator_won)), 0)
+             FROM matches WHERE creator_id = ?1 OR participant_id = ?1",
+            params![player_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).map_err(|err| err.to_string())?;
+        // Draws are neither won nor lost
+        let (hands, hands_won, hands_lost) : (u32, u32, u32) = conn.query_row(
+            "SELECT COUNT(*),
+                COALESCE(SUM((m.creator_id = ?1 AND h.creator_won) OR (m.participant_id = ?1 AND NOT h.creator_won)), 0),
+                COALESCE(SUM((m.creator_id = ?1 AND NOT h.creator_won) OR (m.participant_id = ?1 AND h.creator_won)), 0)
+             FROM hands h JOIN matches m ON m.id = h.match_id
+             WHERE m.creator_id = ?1 OR m.participant_id = ?1",
+            params![player_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).map_err(|err| err.to_string())?;
+
+        Ok(Some(Profile {
+            account,
+            created: created as u64,
+            matches,
+            wins,
+            losses: matches - wins,
+            hands,
+            hands_won,
+            hands_lost,
+        }))
+    }
+
+    // Latest matches first. None when there is no such player.
+    pub fn recent_matches(&self, player_id: &str, limit: u32) -> Result<Option<Vec<MatchSummary>>, String> {
+        let conn = self.lock();
+        let exists = conn.query_row("SELECT 1 FROM players WHERE id = ?1", params![player_id], |_| Ok(()))
+            .optional().map_err(|err| err.to_string())?;
+        if exists.is_none() {
+            return Ok(None);
+        }
+
+        let mut statement = conn.prepare(
+            "SELECT id, room_id, game, mode, stakes, creator_id, creator_name, participant_id, participant_name,
+                creator_won, creator_hp, participant_hp, finished
+             FROM matches WHERE creator_id = ?1 OR participant_id = ?1
+             ORDER BY finished DESC, id DESC LIMIT ?2",
+        ).map_err(|err| err.to_string())?;
+        let mut matches = statement
+            .query_map(params![player_id, limit], |row| match_summary(row, player_id))
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| err.to_string())?;
+
+        let mut statement = conn.prepare(
+            "SELECT number, creator_won, creator_fold, participant_fold, creator_comb, participant_comb,
+                pot, creator_hp, participant_hp
+             FROM hands WHERE match_id = ?1 ORDER BY number",
+        ).map_err(|err| err.to_string())?;
+        for (summary, is_creator) in matches.iter_mut() {
+            summary.hands = statement
+                .query_map(params![summary.id], |row| hand_summary(row, *is_creator))
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(Some(matches.into_iter().map(|(summary, _)| summary).collect()))
+    }
+
+    // Statements don't panic while holding the lock,
+    // but a poisoned lock must not stop every other game from being stored.
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl AccountStore for Storage {
+    fn create(&self, name: &str, password_hash: &str) -> Result<Account, AuthError> {
+        let account = Account {
+            id: Uuid::new_v4().to_simple().to_string(),
+            name: name.to_string(),
+        };
+        let result = self.lock().execute(
+            "INSERT INTO players (id, name, name_key, password_hash, created) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![account.id, account.name, name.to_lowercase(), password_hash, now_millis() as i64],
+        );
+        match result {
+            Ok(_) => Ok(account),
+            Err(rusqlite::Error::SqliteFailure(err, _)) if err.code == ErrorCode::ConstraintViolation => Err(AuthError::NameTaken),
+            Err(err) => Err(AuthError::Storage(err.to_string())),
+        }
+    }
+
+    fn find_by_name(&self, name: &str) -> Result<Option<(Account, String)>, AuthError> {
+        self.lock().query_row(
+            "SELECT id, name, password_hash FROM players WHERE name_key = ?1",
+            params![name.to_lowercase()],
+            |row| Ok((Account { id: row.get(0)?, name: row.get(1)? }, row.get(2)?)),
+        ).optional().map_err(|err| AuthError::Storage(err.to_string()))
+    }
+}
+
+// Row of matches table turned to the side of player, and whether player was creator
+fn match_summary(row: &Row, player_id: &str) -> rusqlite::Result<(MatchSummary, bool)> {
+    let is_creator = row.get::<_, Option<String>>(5)?.as_deref() == Some(player_id);
+    let (opp_id, opp_name, hp, opp_hp) = if is_creator {
+        (row.get(7)?, row.get(8)?, row.get(10)?, row.get(11)?)
+    } else {
+        (row.get(5)?, row.get(6)?, row.get(11)?, row.get(10)?)
+    };
+    let creator_won : bool = row.get(9)?;
+    Ok((MatchSummary {
+        id: row.get(0)?,
+        room_id: row.get(1)?,
+        game: row.get(2)?,
+        mode: from_text(&row.get::<_, String>(3)?).unwrap_or(GameMode::Single),
+        stakes: row.get(4)?,
+        opp_name,
+        opp_id,
+        win: creator_won == is_creator,
+        hp,
+        opp_hp,
+        hands: vec![],
+        finished: row.get::<_, i64>(12)? as u64,
+    }, is_creator))
+}
+
+fn hand_summary(row: &Row, is_creator: bool) -> rusqlite::Result<HandSummary> {
+    let creator_won : Option<bool> = row.get(1)?;
+    let comb = |index| -> rusqlite::Result<Option<CardCombination>> {
+        Ok(row.get::<_, Option<String>>(index)?.and_then(|text| from_text(&text)))
+    };
+    let (fold, opp_fold, comb, opp_comb, hp, opp_hp) = if is_creator {
+        (row.get(2)?, row.get(3)?, comb(4)?, comb(5)?, row.get(7)?, row.get(8)?)
+    } else {
+        (row.get(3)?, row.get(2)?, comb(5)?, comb(4)?, row.get(8)?, row.get(7)?)
+    };
+    Ok(HandSummary {
+        number: row.get(0)?,
+        win: creator_won.map(|creator_won| creator_won == is_creator),
+        fold,
+        opp_fold,
+        comb,
+        opp_comb,
+        pot: row.get(6)?,
+        hp,
+        opp_hp,
+    })
+}
+
+// Enums are stored with the same names as in the protocol
+fn to_text<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(text)) => text,
+        _ => String::new(),
+    }
+}
+
+fn from_text<T: DeserializeOwned>(text: &str) -> Option<T> {
+    serde_json::from_value(serde_json::Value::String(text.to_string())).ok()
+}
+
+pub async fn profile_handler(player_id: String, storage: Storage) -> Result<impl warp::Reply, Infallible> {
+    let result = tokio::task::spawn_blocking(move || storage.profile(&player_id)).await;
+    Ok(lookup_reply(result))
+}
+
+pub async fn matches_handler(player_id: String, query: MatchesQuery, storage: Storage) -> Result<impl warp::Reply, Infallible> {
+    let limit = query.limit.unwrap_or(DEFAULT_MATCHES).min(MAX_MATCHES);
+    let result = tokio::task::spawn_blocking(move || storage.recent_matches(&player_id, limit)).await;
+    Ok(lookup_reply(result))
+}
+
+fn lookup_reply<T: Serialize>(result: Result<Result<Option<T>, String>, tokio::task::JoinError>) -> warp::reply::WithStatus<warp::reply::Json> {
+    let error = |message: &str, status| warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status,
+    );
+    match result.unwrap_or_else(|err| Err(err.to_string())) {
+        Ok(Some(value)) => warp::reply::with_status(warp::reply::json(&value), StatusCode::OK),
+        Ok(None) => error("Player not found", StatusCode::NOT_FOUND),
+        Err(err) => {
+            eprintln!("Storage error : {}", err);
+            error("Storage error", StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub fn with_storage(storage: Storage) -> impl Filter<Extract = (Storage,), Error = Infallible> + Clone {
+    warp::any().map(move || storage.clone())
+}
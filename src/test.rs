@@ -1,6 +1,29 @@
-use crate::models::{CardPool, Card, CardType, CombinationBuilder};
+use crate::models::{Connection, CreateOption, LobbyEvent, SpectatorBet, GameMode, RoomFilter, WatchOption, Seat, ResponseType, RoomAccess, CardPool, Card, CardType, CardCombination, CombinationBuilder, Series, TurnTimer, now_millis, Game, Rules, Timers, GameState, PlayerAction, UserRequest, ServerResponse, ResponseValue};
+use crate::agent::{ChannelAgent, PlayerAgent, ReplayAgent, WebSocketAgent, play_seats};
+use crate::protocol::{ClientHello, ClientInfo, ProtocolVersion, WireFormat};
+use crate::schema::{samples, schemas};
+use tokio::sync::{mpsc, oneshot};
+use warp::ws::Message;
+use crate::bot::{Bot, BotLevel, estimate_equity};
+use crate::room::{RoomCommand, RoomConfig, RoomTimer, RoomTimers, Rooms};
+use crate::config::ServerConfig;
+use crate::tls::{self, TlsCerts};
+use crate::routes;
+use crate::accounts::{Account, AccountStore, Accounts, AuthError, Credentials, client_hello};
+use crate::storage::Storage;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::webpki::DNSNameRef;
+use warp::Filter;
+use crate::heartbeat::{Heartbeat, HeartbeatConfig};
+use crate::outbox::{Outbox, OutboxConfig, OutboxReceiver, Outboxes, Priority, SlowClientPolicy};
+use futures::FutureExt;
+use crate::matchmaking::{Queue, QueueEntry, leave_queue};
+use crate::handlers::{Lobby, listed_rooms, resolve_room, user_action_handler, user_disconnected_handler};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use rand::prelude::*;
-use rand::distributions::{Distribution, Uniform};
 
 #[test]
 fn function_name_test() {
@@ -24,6 +47,8 @@ fn function_name_test() {
     //println!("{:?}", array);
 }
 
+// Call in function_name_test is commented out
+#[allow(dead_code, unused_mut, clippy::unnecessary_sort_by)]
 fn manual_comb_test() {
     let mut cards: Vec<Card> = vec![
         Card::new(CardType::Spade, 10),
@@ -45,6 +70,7 @@ fn manual_comb_test() {
     println!("Highest combination is : {:?}, meta : {:?}", highest, meta);
 }
 
+#[allow(unused_mut, clippy::unnecessary_sort_by, clippy::useless_vec)]
 fn rand_comb_test() {
     let mut card_pool = CardPool::new();
     let mut cards = vec![];
@@ -71,3 +97,1272 @@ fn rand_comb_test() {
     let ( highest , meta) = CombinationBuilder::get_highest_combination(merged);
     println!("Highest combination is : {:?}, meta :{:?}", highest, meta);
 }
+
+#[test]
+fn series_test() {
+    let mut series = Series::new(3);
+    series.record(true);
+    assert!(!series.is_decided());
+    series.record(false);
+    assert!(!series.is_decided());
+    series.record(true);
+    assert!(series.is_decided());
+}
+
+#[test]
+fn spectator_test() {
+    let mut table = Table::started();
+    let Table { game, creator_rx, .. } = &mut table;
+    let (spectator_tx, mut spectator_rx) = outbox();
+    let client = ClientHello { version: Some("1.1".to_string()), capabilities: None, format: None, auth: None, account: None }.client_info().unwrap();
+    game.add_spectator("spectator".to_string(), spectator_tx, client.clone(), 0);
+
+    // Spectator cannot act in the game
+    replay(game, "spectator", PlayerAction::Fold, None);
+    assert!(matches!(game.state, GameState::Flop));
+
+    // Folded hand is not revealed at showdown
+    replay(game, "participant", PlayerAction::Fold, None);
+    assert!(matches!(game.state, GameState::ShowDown));
+    let creator_result = drain_events(creator_rx).into_iter()
+        .find_map(|res| if let ResponseValue::RoundResult(result) = res.value { Some(result) } else { None })
+        .expect("Creator didn't get round result");
+    assert_eq!(creator_result.win, Some(true));
+
+    let events = received(&mut spectator_rx).iter()
+        .map(|msg| client.decode::<ServerResponse>(msg).unwrap())
+        .collect::<Vec<ServerResponse>>();
+    assert!(!events.iter().any(|res| matches!(res.response_type, ResponseType::Hand)));
+
+    // Round result of spectator doesn't tell folded combination
+    if let Some(ResponseValue::RoundResult(result)) = events.iter().map(|res| &res.value).find(|value| matches!(value, ResponseValue::RoundResult(_))) {
+        assert_eq!(result.win, Some(true));
+        assert!(result.opp_fold);
+        assert_eq!(result.comb as u8, creator_result.comb as u8);
+        assert_eq!(result.user_meta, creator_result.user_meta);
+        assert_eq!(result.opp_comb as u8, CardCombination::HighCard as u8);
+        assert_eq!(result.opp_meta, None);
+    } else {
+        panic!("Spectator didn't get round result");
+    }
+    if let Some(ResponseValue::Reveal(reveal)) = events.iter().map(|res| &res.value).find(|value| matches!(value, ResponseValue::Reveal(_))) {
+        assert_eq!(reveal.creator_cards, game.creator.stat.cards);
+        assert!(reveal.participant_cards.is_empty());
+    } else {
+        panic!("Hands were not revealed");
+    }
+
+    // Delay is optional and limited
+    assert_eq!(WatchOption { delay: None, password: None }.delay(), 0);
+    assert_eq!(WatchOption { delay: Some(u64::MAX), password: None }.delay(), 300);
+}
+
+#[test]
+fn equity_test() {
+    let community = vec![
+        Card::new(CardType::Spade, 10),
+        Card::new(CardType::Spade, 11),
+        Card::new(CardType::Spade, 12),
+    ];
+    let hand = vec![
+        Card::new(CardType::Spade, 9),
+        Card::new(CardType::Spade, 13),
+    ];
+
+    let equity = estimate_equity(&hand, &community, 300);
+    assert!(equity > 0.8 && equity <= 1.0);
+}
+
+#[tokio::test]
+async fn bot_max_raise_test() {
+    tokio::time::pause();
+    let (action_tx, mut action_rx) = mpsc::unbounded_channel();
+    let (event_tx, event_rx) = mpsc::unbounded_channel();
+    tokio::task::spawn(Bot::new(BotLevel::Equity, action_tx).run(event_rx));
+
+    let send = |response_type, value| event_tx.send(ServerResponse::new(response_type, value)).unwrap();
+    send(ResponseType::Hand, ResponseValue::Card(vec![Card::new(CardType::Spade, 9), Card::new(CardType::Heart, 2)]));
+    send(ResponseType::Community, ResponseValue::Card(vec![
+        Card::new(CardType::Clover, 4),
+        Card::new(CardType::Diamond, 7),
+        Card::new(CardType::Spade, 13),
+    ]));
+    send(ResponseType::State, ResponseValue::State((GameState::Flop, "flop".to_string())));
+
+    // Pot odds of the largest raise don't overflow
+    send(ResponseType::Raise, ResponseValue::Raise(u32::MAX));
+    tokio::time::advance(std::time::Duration::from_secs(2)).await;
+    let req = action_rx.recv().await.expect("Bot didn't act against raise");
+    assert_eq!(req.state_id, "flop");
+    assert!(matches!(req.action, PlayerAction::Call | PlayerAction::Fold));
+}
+
+// Scripted seat which replays given actions for the current state
+fn replay(game: &mut Game, uid: &str, action: PlayerAction, value: Option<u32>) {
+    let req = UserRequest {
+        state_id: game.state_id.clone().unwrap(),
+        action,
+        value,
+        text: None,
+        emote: None,
+    };
+    let pending = game.receive_player_action(uid, req);
+    game.pending_next_state(pending);
+}
+
+fn drain_events(receiver: &mut mpsc::UnboundedReceiver<ServerResponse>) -> Vec<ServerResponse> {
+    let mut events = vec![];
+    while let Ok(res) = receiver.try_recv() {
+        events.push(res);
+    }
+    events
+}
+
+// Game between two channel agents so that tests can drive both seats
+struct Table {
+    game: Game,
+    creator_rx: mpsc::UnboundedReceiver<ServerResponse>,
+    part_rx: mpsc::UnboundedReceiver<ServerResponse>,
+    part_tx: Option<mpsc::UnboundedSender<ServerResponse>>,
+    commands_rx: mpsc::UnboundedReceiver<RoomCommand>,
+}
+
+impl Table {
+    // Only creator is seated
+    fn new() -> Self {
+        let (creator_tx, creator_rx) = mpsc::unbounded_channel();
+        let (part_tx, part_rx) = mpsc::unbounded_channel();
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+
+        Self {
+            game: Game::new("creator".to_string(), Box::new(ChannelAgent::new(creator_tx, None)), commands_tx, Rules::default(), Timers::default()),
+            creator_rx,
+            part_rx,
+            part_tx: Some(part_tx),
+            commands_rx,
+        }
+    }
+
+    fn join(&mut self) {
+        let part_tx = self.part_tx.take().expect("Participant has already joined");
+        self.game.join_game("participant".to_string(), Box::new(ChannelAgent::new(part_tx, None)));
+    }
+
+    // Both players are seated and first round has started
+    fn started() -> Self {
+        let mut table = Self::new();
+        table.join();
+        table.game.init_game();
+        table
+    }
+}
+
+type Seats = Vec<(String, mpsc::UnboundedReceiver<UserRequest>)>;
+
+// Seat agents which produce their own actions and start the game.
+// Actions are played with play_seats.
+fn seat_agents(
+    mut creator: impl PlayerAgent + 'static,
+    mut participant: impl PlayerAgent + 'static,
+) -> (Game, Seats, mpsc::UnboundedReceiver<RoomCommand>) {
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let seats = vec![
+        ("creator".to_string(), creator.take_actions().unwrap()),
+        ("participant".to_string(), participant.take_actions().unwrap()),
+    ];
+
+    let mut game = Game::new("creator".to_string(), Box::new(creator), commands_tx, Rules::default(), Timers::default());
+    game.join_game("participant".to_string(), Box::new(participant));
+    game.init_game();
+    (game, seats, commands_rx)
+}
+
+#[test]
+fn agent_harness_test() {
+    // Harness seats send actions through agent's action channel
+    // and room plays them same as any other seat.
+    let (creator_tx, mut creator_rx) = mpsc::unbounded_channel();
+    let (part_tx, mut part_rx) = mpsc::unbounded_channel();
+    let (creator_actions, creator_action_rx) = mpsc::unbounded_channel();
+    let (part_actions, part_action_rx) = mpsc::unbounded_channel();
+    let (mut game, mut seats, _commands_rx) = seat_agents(
+        ChannelAgent::new(creator_tx, Some(creator_action_rx)),
+        ChannelAgent::new(part_tx, Some(part_action_rx)),
+    );
+
+    let events = drain_events(&mut part_rx);
+    assert!(events.iter().any(|res| matches!(res.value, ResponseValue::State((GameState::Flop, _)))));
+    drain_events(&mut creator_rx);
+
+    // Both players check, then game moves to turn
+    for actions in [&creator_actions, &part_actions].iter() {
+        actions.send(UserRequest {
+            state_id: game.state_id.clone().unwrap(),
+            action: PlayerAction::Check,
+            value: Some(0),
+            text: None,
+            emote: None,
+        }).unwrap();
+    }
+    play_seats(&mut game, &mut seats);
+    assert!(matches!(game.state, GameState::Turn));
+
+    let events = drain_events(&mut creator_rx);
+    assert!(events.iter().any(|res| matches!(res.value, ResponseValue::BetResult(_))));
+    assert!(events.iter().any(|res| matches!(res.value, ResponseValue::State((GameState::Turn, _)))));
+}
+
+#[test]
+fn replay_agent_test() {
+    let (creator_tx, mut creator_rx) = mpsc::unbounded_channel();
+    let (part_tx, _part_rx) = mpsc::unbounded_channel();
+
+    // Creator raises on flop and participant folds
+    let (mut game, mut seats, _commands_rx) = seat_agents(
+        ReplayAgent::new(vec![(PlayerAction::Raise, Some(1))], creator_tx),
+        ReplayAgent::new(vec![(PlayerAction::Fold, None)], part_tx),
+    );
+    play_seats(&mut game, &mut seats);
+
+    assert!(matches!(game.state, GameState::ShowDown));
+    assert!(game.participant.as_ref().unwrap().stat.fold);
+    let events = drain_events(&mut creator_rx);
+    assert!(events.iter().any(|res| matches!(&res.value, ResponseValue::RoundResult(result) if result.win == Some(true))));
+}
+
+#[test]
+fn turn_timer_test() {
+    let mut timer = TurnTimer::new(&Timers::default());
+    let bank = timer.bank;
+
+    // Acting in bet time doesn't use time bank
+    timer.start(0);
+    timer.stop(1000);
+    assert_eq!(timer.bank, bank);
+
+    // Taking 5 seconds longer than bet time uses 5 seconds of time bank
+    timer.start(10_000);
+    let deadline = timer.deadline.unwrap();
+    timer.stop(deadline - bank + 5000);
+    assert_eq!(timer.bank, bank - 5000);
+    assert!(timer.deadline.is_none());
+
+    timer.start(0);
+    assert!(!timer.is_expired(1000));
+    assert!(timer.is_expired(timer.deadline.unwrap()));
+}
+
+#[test]
+fn raise_timer_test() {
+    let mut table = Table::started();
+    let game = &mut table.game;
+    let bank = game.creator.stat.timer.bank;
+
+    // Creator has taken 5 seconds longer than bet time
+    let timer = &mut game.creator.stat.timer;
+    timer.turn_start -= timer.bet_time + 5000;
+    replay(game, "participant", PlayerAction::Raise, Some(1));
+
+    // Raise gives a new turn but doesn't refill used time bank
+    let timer = &game.creator.stat.timer;
+    assert!(timer.bank <= bank - 5000);
+    assert!(timer.deadline.unwrap() <= now_millis() + timer.bet_time + bank - 5000);
+}
+
+#[test]
+fn sit_out_test() {
+    let mut table = Table::new();
+    table.join();
+    let game = &mut table.game;
+
+    // Repeated timeouts make player sit out
+    assert!(!game.creator.register_timeout());
+    assert!(game.creator.register_timeout());
+    game.init_game();
+    assert_eq!(game.creator.current_action, PlayerAction::Check);
+    drain_events(&mut table.part_rx);
+
+    // Sitting out player folds against raise
+    replay(game, "participant", PlayerAction::Raise, Some(1));
+    assert!(game.creator.stat.fold);
+    assert!(matches!(game.state, GameState::ShowDown));
+
+    // Any action brings player back
+    assert!(game.creator.register_action());
+    assert!(!game.creator.sitting_out);
+}
+
+#[test]
+fn raise_against_away_test() {
+    // Raise of 0 or overflowing raise against away player doesn't panic
+    for value in [0, u32::MAX].iter() {
+        let mut table = Table::started();
+        let Table { game, part_rx, .. } = &mut table;
+        drain_events(part_rx);
+
+        assert!(game.disconnect_user("creator", 0));
+        replay(game, "participant", PlayerAction::Raise, Some(*value));
+        assert!(game.creator.stat.fold);
+        assert!(matches!(game.state, GameState::ShowDown));
+        assert_eq!(game.participant.as_ref().unwrap().stat.bet, value.saturating_sub(1));
+    }
+}
+
+#[test]
+fn disconnect_test() {
+    let mut table = Table::started();
+    let Table { game, part_rx, .. } = &mut table;
+    drain_events(part_rx);
+
+    // Disconnected player checks automatically and opponent is notified
+    assert!(game.disconnect_user("creator", 0));
+    assert_eq!(game.creator.current_action, PlayerAction::Check);
+    let events = drain_events(part_rx);
+    assert!(events.iter().any(|res| matches!(res.value, ResponseValue::Connection(_))));
+
+    // Outdated session is ignored
+    assert!(!game.disconnect_user("creator", 1));
+
+    replay(game, "participant", PlayerAction::Check, Some(0));
+    assert!(matches!(game.state, GameState::Turn));
+    assert!(game.is_grace_expired("creator", 0));
+}
+
+#[tokio::test]
+async fn resume_test() {
+    let Table { game, mut part_rx, commands_rx, .. } = Table::started();
+    let (commands_tx, _room_commands_rx) = mpsc::unbounded_channel();
+    let (room_tx, _room_rx) = mpsc::unbounded_channel();
+    let mut connection = Connection::new("creator".to_string(), "room".to_string(), Box::new(ChannelAgent::new(room_tx, None)), commands_tx, Rules::default(), Timers::default());
+    connection.game = game;
+    let game = &mut connection.game;
+    drain_events(&mut part_rx);
+
+    assert!(game.disconnect_user("creator", 0));
+    let events = drain_events(&mut part_rx);
+    assert!(events.iter().any(|res| matches!(&res.value, ResponseValue::Connection(status) if !status.opp_connected)));
+
+    // Resumed user gets snapshot and opponent is told that user is back
+    let (resumed_tx, mut resumed_rx) = mpsc::unbounded_channel();
+    let token = game.creator.token.clone();
+    let resumed = game.resume_user(&token, Box::new(ChannelAgent::new(resumed_tx, None)));
+    assert_eq!(resumed, Some(("creator".to_string(), 1)));
+    let events = drain_events(&mut resumed_rx);
+    assert!(events.iter().any(|res| matches!(res.value, ResponseValue::Snapshot(_))));
+    let events = drain_events(&mut part_rx);
+    assert!(events.iter().any(|res| matches!(&res.value, ResponseValue::Connection(status) if status.opp_connected)));
+
+    // Old socket closing after resume is ignored
+    assert!(!game.disconnect_user("creator", 0));
+    assert!(game.creator.connected);
+
+    // Old socket can't act on the seat, resumed one can
+    let state_id = game.state_id.clone().unwrap();
+    let fold = || UserRequest {
+        state_id: state_id.clone(),
+        action: PlayerAction::Fold,
+        value: None,
+        text: None,
+        emote: None,
+    };
+    let lobby: Lobby = Arc::new(RwLock::new(HashMap::new()));
+    let room = Rooms::default().open(connection, commands_rx, &lobby);
+
+    // Room task handles messages in order, thus call sees the result of the action
+    user_action_handler(&room, "creator", 0, fold());
+    assert_eq!(room.call(|connection| connection.game.creator.stat.fold).await, Some(false));
+    user_action_handler(&room, "creator", 1, fold());
+    assert_eq!(room.call(|connection| connection.game.creator.stat.fold).await, Some(true));
+}
+
+#[test]
+fn resync_test() {
+    let mut table = Table::started();
+    let Table { game, part_rx, .. } = &mut table;
+    drain_events(part_rx);
+
+    // Resync is accepted even with outdated state id
+    let req = UserRequest {
+        state_id: "outdated".to_string(),
+        action: PlayerAction::Resync,
+        value: None,
+        text: None,
+        emote: None,
+    };
+    game.receive_player_action("participant", req);
+
+    let events = drain_events(part_rx);
+    if let Some(ResponseValue::Snapshot(snapshot)) = events.last().map(|res| &res.value) {
+        assert_eq!(snapshot.state_id, game.state_id);
+        assert_eq!(snapshot.hand, game.participant.as_ref().unwrap().stat.cards);
+        assert_eq!(snapshot.community, game.community);
+    } else {
+        panic!("Snapshot was not sent");
+    }
+}
+
+#[tokio::test]
+async fn private_room_test() {
+    let (creator_tx, _creator_rx) = mpsc::unbounded_channel();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let mut connection = Connection::new("creator".to_string(), "room".to_string(), Box::new(ChannelAgent::new(creator_tx, None)), commands_tx, Rules::default(), Timers::default());
+
+    assert_eq!(connection.invite_code.len(), 6);
+    assert!(!connection.invite_code.contains(|c| "0O1IL".contains(c)));
+    assert!(connection.is_listed());
+    assert!(connection.access.check_password(None));
+
+    connection.access = RoomAccess { private: false, password: Some("secret".to_string()) };
+    assert!(!connection.is_listed());
+    assert!(!connection.access.check_password(None));
+    assert!(!connection.access.check_password(Some("wrong")));
+    assert!(connection.access.check_password(Some("secret")));
+
+    // Join and watch resolve room by id or invite code and check password
+    let invite_code = connection.invite_code.to_lowercase();
+    let rooms = Rooms::default();
+    let lobby: Lobby = Arc::new(RwLock::new(HashMap::new()));
+    rooms.open(connection, commands_rx, &lobby);
+    let resolved = |room_key: &str, password| resolve_room(room_key, password, &rooms).map(|entry| entry.info.room_id);
+    assert_eq!(resolved("room", Some("secret")), Ok("room".to_string()));
+    assert_eq!(resolved(&invite_code, Some("secret")), Ok("room".to_string()));
+    assert!(resolved(&invite_code, None).is_err());
+    assert!(resolved("unknown", Some("secret")).is_err());
+}
+
+#[tokio::test]
+async fn room_filter_test() {
+    // Stakes out of range are rejected
+    assert!(Rules::with_options(Rules::default(), None, Some(0)).is_err());
+    assert!(Rules::with_options(Rules::default(), None, Some(1000)).is_err());
+    let rules = Rules::with_options(Rules::default(), Some(GameMode::Single), Some(2)).unwrap();
+    assert_eq!(rules.stakes, 2);
+
+    let filter = |mode, stakes| RoomFilter { mode, stakes };
+    assert!(filter(None, None).matches(&rules));
+    assert!(filter(Some(GameMode::Single), Some(2)).matches(&rules));
+    assert!(!filter(Some(GameMode::Series), None).matches(&rules));
+    assert!(!filter(None, Some(1)).matches(&rules));
+
+    // Private and password protected rooms are not listed
+    let rooms = Rooms::default();
+    let lobby: Lobby = Arc::new(RwLock::new(HashMap::new()));
+    for (room_id, access) in [
+        ("public", RoomAccess::default()),
+        ("private", RoomAccess { private: true, password: None }),
+        ("password", RoomAccess { private: false, password: Some("secret".to_string()) }),
+    ].iter().cloned() {
+        let (creator_tx, _) = mpsc::unbounded_channel();
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let mut connection = Connection::new("creator".to_string(), room_id.to_string(), Box::new(ChannelAgent::new(creator_tx, None)), commands_tx, rules, Timers::default());
+        connection.access = access;
+        rooms.open(connection, commands_rx, &lobby);
+    }
+
+    let listed = listed_rooms(&filter(None, None), &rooms);
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].room_id, "public");
+    assert!(listed_rooms(&filter(Some(GameMode::Series), None), &rooms).is_empty());
+
+    // Only exact path lists rooms
+    let get_rooms = routes::get_rooms(&rooms);
+    let res = warp::test::request().path("/rooms?stakes=1").reply(&get_rooms).await;
+    assert_eq!(res.status(), 200);
+    let res = warp::test::request().path("/rooms/public").reply(&get_rooms).await;
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn room_registry_test() {
+    tokio::time::pause();
+    let (creator_tx, _creator_rx) = mpsc::unbounded_channel();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let connection = Connection::new("creator".to_string(), "room".to_string(), Box::new(ChannelAgent::new(creator_tx, None)), commands_tx, Rules::default(), Timers::default());
+    let token = connection.game.creator.token.clone();
+
+    let rooms = Rooms::default();
+    let lobby: Lobby = Arc::new(RwLock::new(HashMap::new()));
+    let hello = ClientHello { version: Some("1.1".to_string()), capabilities: Some("lobby".to_string()), format: None, auth: None, account: None };
+    let (lobby_tx, mut lobby_rx) = outbox();
+    lobby.write().unwrap().insert("lobby".to_string(), (lobby_tx, hello.client_info().unwrap()));
+    let room = rooms.open(connection, commands_rx, &lobby);
+    assert!(rooms.get("room").is_some());
+    assert!(rooms.find_token(&token).is_some());
+
+    // Room task reports new seat to registry
+    let (part_tx, _part_rx) = mpsc::unbounded_channel();
+    room.call(|connection| connection.game.join_game("participant".to_string(), Box::new(ChannelAgent::new(part_tx, None)))).await;
+    room.call(|_| ()).await;
+    assert_eq!(rooms.find("room").unwrap().info.seats_taken, 2);
+
+    // Seat is kept for grace period and room is closed after it.
+    // Paused clock also jumps to the timer whenever tasks are idle.
+    let disconnected = tokio::time::Instant::now();
+    user_disconnected_handler(&room, "participant", 0);
+    assert_eq!(room.call(|connection| connection.game.participant.is_some()).await, Some(true));
+    while room.call(|_| ()).await.is_some() {
+        tokio::time::advance(std::time::Duration::from_secs(1)).await;
+    }
+    assert!(disconnected.elapsed() >= std::time::Duration::from_secs(60));
+    assert!(rooms.get("room").is_none());
+    assert!(rooms.find_token(&token).is_none());
+
+    let mut closed = false;
+    for msg in received(&mut lobby_rx) {
+        let res: ServerResponse = serde_json::from_str(msg.to_str().unwrap()).unwrap();
+        closed |= matches!(&res.value, ResponseValue::Lobby(LobbyEvent::RoomClosed(room_id)) if room_id == "room");
+    }
+    assert!(closed);
+}
+
+#[tokio::test]
+async fn outbox_test() {
+    let text = Message::text;
+    let outboxes = |policy| Outboxes::new(OutboxConfig::new(0, policy));
+    let capacity = OutboxConfig::new(0, SlowClientPolicy::Drop).capacity;
+    assert_eq!(capacity, 16);
+
+    // Optional frames are given up first and critical ones still fit
+    let dropping = outboxes(SlowClientPolicy::Drop);
+    let (sender, mut receiver) = dropping.channel(true);
+    for _ in 0..capacity {
+        assert!(sender.send(Priority::Optional, text("chat")));
+    }
+    assert!(sender.send(Priority::Optional, text("chat")));
+    assert!(sender.send(Priority::Critical, text("result")));
+    let frames = received(&mut receiver);
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].to_str(), Ok("result"));
+    assert!(dropping.metrics().contains("card_outbox_dropped_total 17\n"));
+
+    // Game updates are merged away until snapshot is sent
+    let (sender, mut receiver) = outboxes(SlowClientPolicy::Merge).channel(true);
+    for _ in 0..capacity {
+        sender.send(Priority::Update, text("state"));
+    }
+    assert!(!sender.take_stale());
+    assert!(sender.send(Priority::Update, text("state")));
+    assert!(sender.send(Priority::Update, text("state")));
+    assert!(received(&mut receiver).is_empty());
+
+    // Seat with stale outbox gets one snapshot from the room
+    let mut table = Table::started();
+    let client = ClientHello { version: Some("1.1".to_string()), capabilities: None, format: None, auth: None, account: None }.client_info().unwrap();
+    table.game.creator.agent = Box::new(WebSocketAgent::new(sender.clone(), client.clone()));
+    table.game.resync_stale();
+    table.game.resync_stale();
+    let events = received(&mut receiver).iter()
+        .map(|msg| client.decode::<ServerResponse>(msg).unwrap())
+        .collect::<Vec<ServerResponse>>();
+    assert_eq!(events.iter().filter(|res| matches!(res.value, ResponseValue::Snapshot(_))).count(), 1);
+    assert!(sender.send(Priority::Update, text("state")));
+    assert_eq!(received(&mut receiver).len(), 1);
+
+    // Client that doesn't know snapshot can't be merged, thus full queue of updates disconnects
+    let (sender, _receiver) = outboxes(SlowClientPolicy::Merge).channel(false);
+    for _ in 0..capacity {
+        sender.send(Priority::Update, text("state"));
+    }
+    assert!(!sender.send(Priority::Update, text("state")));
+
+    // Socket is closed with close frame and receiver ends
+    let disconnecting = outboxes(SlowClientPolicy::Disconnect);
+    let (sender, mut receiver) = disconnecting.channel(true);
+    for _ in 0..capacity {
+        assert!(sender.send(Priority::Critical, text("result")));
+    }
+    assert!(disconnecting.metrics().contains("card_outbox_max_depth 16\n"));
+    assert!(!sender.send(Priority::Optional, text("chat")));
+    assert!(!sender.send(Priority::Critical, text("result")));
+    let frames = received(&mut receiver);
+    assert!(frames.len() == 1 && frames[0].is_close());
+    assert_eq!(receiver.next().now_or_never(), Some(None));
+    assert!(disconnecting.metrics().contains("card_outbox_disconnected_total 1\n"));
+
+    // Metrics are served only when enabled
+    let outboxes = Outboxes::default();
+    let res = warp::test::request().path("/metrics").reply(&routes::get_metrics(&outboxes, false)).await;
+    assert_eq!(res.status(), 404);
+    let get_metrics = routes::get_metrics(&outboxes, true);
+    let res = warp::test::request().path("/metrics").reply(&get_metrics).await;
+    assert_eq!(res.status(), 200);
+    assert!(String::from_utf8_lossy(res.body()).contains("card_outbox_dropped_total 0\n"));
+    let res = warp::test::request().path("/metrics/extra").reply(&get_metrics).await;
+    assert_eq!(res.status(), 404);
+
+    // Receiver ends after queued frames when every sender is gone
+    let (sender, mut receiver) = outbox();
+    sender.send(Priority::Critical, text("last"));
+    drop(sender);
+    assert_eq!(received(&mut receiver).len(), 1);
+    assert_eq!(receiver.next().now_or_never(), Some(None));
+}
+
+fn queue_entry(id: &str, rating: Option<u32>) -> (QueueEntry, oneshot::Receiver<String>) {
+    let (sender, _) = outbox();
+    let (matched_tx, matched_rx) = oneshot::channel();
+    let client = ClientHello::default().client_info().unwrap();
+    (QueueEntry::new(id.to_string(), Rules::default(), rating, sender, client, matched_tx), matched_rx)
+}
+
+#[tokio::test]
+async fn queue_entry_test() {
+    tokio::time::pause();
+    let (entry, _) = queue_entry("waiting", Some(1000));
+
+    // Only same rules are matched
+    let single = Rules::with_options(Rules::default(), Some(GameMode::Single), None).unwrap();
+    assert!(entry.accepts(&Rules::default(), Some(1100), None));
+    assert!(!entry.accepts(&single, Some(1000), None));
+    // Rating is ignored unless both players have one
+    assert!(entry.accepts(&Rules::default(), None, None));
+
+    // Rating range grows while waiting
+    assert!(!entry.accepts(&Rules::default(), Some(1150), None));
+    tokio::time::advance(std::time::Duration::from_secs(10)).await;
+    assert!(entry.accepts(&Rules::default(), Some(1150), None));
+    assert!(!entry.accepts(&Rules::default(), Some(1200), None));
+
+    // Same account is never paired with itself
+    let account = |id: &str| Account { id: id.to_string(), name: id.to_string() };
+    let (sender, _) = outbox();
+    let (matched_tx, _matched_rx) = oneshot::channel();
+    let client = ClientInfo { account: Some(account("alice")), ..ClientHello::default().client_info().unwrap() };
+    let entry = QueueEntry::new("signed".to_string(), Rules::default(), None, sender, client, matched_tx);
+    assert!(!entry.accepts(&Rules::default(), None, Some(&account("alice"))));
+    assert!(entry.accepts(&Rules::default(), None, Some(&account("bob"))));
+    assert!(entry.accepts(&Rules::default(), None, None));
+}
+
+#[test]
+fn leave_queue_test() {
+    let queue: Queue = Arc::new(RwLock::new(Vec::new()));
+
+    // Player who is still waiting is simply removed
+    let (entry, mut matched_rx) = queue_entry("waiting", None);
+    queue.write().unwrap().push(entry);
+    assert_eq!(leave_queue(&queue, "waiting", &mut matched_rx), None);
+    assert!(queue.read().unwrap().is_empty());
+
+    // Opponent took player out of queue right before cancel or timeout,
+    // player goes to the room instead of leaving.
+    let (matched_tx, mut matched_rx) = oneshot::channel();
+    matched_tx.send("room".to_string()).unwrap();
+    assert_eq!(leave_queue(&queue, "matched", &mut matched_rx), Some("room".to_string()));
+
+    // Opponent left right before match
+    let (_, mut matched_rx) = queue_entry("dropped", None);
+    assert_eq!(leave_queue(&queue, "dropped", &mut matched_rx), None);
+}
+
+#[tokio::test]
+async fn heartbeat_test() {
+    tokio::time::pause();
+    let secs = std::time::Duration::from_secs;
+
+    // Zero interval and timeout shorter than interval are clamped
+    let config = HeartbeatConfig::new(0, 0);
+    assert_eq!((config.interval, config.timeout), (secs(1), secs(2)));
+    let config = HeartbeatConfig::new(10, 5);
+    assert_eq!((config.interval, config.timeout), (secs(10), secs(20)));
+
+    // Frame from client is passed on
+    let config = HeartbeatConfig::new(10, 30);
+    let (ping_tx, mut ping_rx) = outbox();
+    let (frame_tx, mut frames) = mpsc::unbounded_channel();
+    frame_tx.send(Ok(Message::text("frame"))).unwrap();
+    let mut heartbeat = Heartbeat::new(ping_tx, config);
+    assert!(matches!(heartbeat.next(&mut frames).await, Some(Ok(msg)) if msg.to_str() == Ok("frame")));
+
+    // Silent peer is pinged every interval and given up after timeout
+    let mut silent = futures::stream::pending();
+    let beat = heartbeat.next(&mut silent);
+    let clock = async {
+        for _ in 0..40 {
+            tokio::time::advance(secs(1)).await;
+        }
+    };
+    let (result, _) = futures::join!(beat, clock);
+    assert!(result.is_none());
+    assert_eq!(drain_frames(&mut ping_rx), 2);
+}
+
+#[tokio::test]
+async fn room_timers_test() {
+    tokio::time::pause();
+    let secs = std::time::Duration::from_secs;
+
+    // Game asks room for timers of current state
+    let mut table = Table::started();
+    let state_id = table.game.state_id.clone().unwrap();
+    let mut timers = RoomTimers::default();
+    while let Ok(command) = table.commands_rx.try_recv() {
+        match command {
+            RoomCommand::Timer { state_id: timer_state, duration } => {
+                assert_eq!(timer_state, state_id);
+                timers.start_state(timer_state, duration);
+            }
+            command => panic!("Unexpected command {:?}", command),
+        }
+    }
+
+    // Timers of previous state are cancelled by timer of new state
+    timers.start_state("turn".to_string(), secs(20));
+    timers.start_state("turn".to_string(), secs(30));
+    timers.start_grace("creator".to_string(), 1, secs(25));
+
+    let expired = async {
+        let mut expired = vec![];
+        for _ in 0..3 {
+            expired.push(timers.next().await);
+        }
+        expired
+    };
+    let clock = async {
+        for _ in 0..40 {
+            tokio::time::advance(secs(1)).await;
+        }
+    };
+    let (expired, _) = futures::join!(expired, clock);
+    assert_eq!(expired, vec![
+        RoomTimer::State("turn".to_string()),
+        RoomTimer::Grace { user_id: "creator".to_string(), session: 1 },
+        RoomTimer::State("turn".to_string()),
+    ]);
+}
+
+fn drain_frames(receiver: &mut OutboxReceiver) -> usize {
+    let frames = received(receiver);
+    assert!(frames.iter().all(|msg| msg.is_ping()));
+    frames.len()
+}
+
+fn outbox() -> (Outbox, OutboxReceiver) {
+    Outboxes::default().channel(true)
+}
+
+// Frames that are queued right now
+fn received(receiver: &mut OutboxReceiver) -> Vec<Message> {
+    let mut frames = vec![];
+    while let Some(Some(msg)) = receiver.next().now_or_never() {
+        frames.push(msg);
+    }
+    frames
+}
+
+fn chat(game: &mut Game, uid: &str, action: PlayerAction, text: Option<&str>) {
+    let req = UserRequest {
+        state_id: String::new(),
+        action,
+        value: None,
+        text: text.map(|text| text.to_string()),
+        emote: None,
+    };
+    game.receive_player_action(uid, req);
+}
+
+#[test]
+fn chat_test() {
+    let mut table = Table::new();
+
+    // Late joiner receives chat sent before joining
+    chat(&mut table.game, "creator", PlayerAction::Message, Some("hello"));
+    table.join();
+    let Table { game, creator_rx, part_rx, .. } = &mut table;
+    let events = drain_events(part_rx);
+    assert!(events.iter().any(|res| matches!(&res.value, ResponseValue::ChatHistory(history) if history.len() == 1)));
+
+    // Message goes to opponent, not back only to sender
+    chat(game, "participant", PlayerAction::Message, Some("hi"));
+    let events = drain_events(creator_rx);
+    assert!(events.iter().any(|res| matches!(&res.value, ResponseValue::Chat(msg) if msg.seat == Seat::Participant)));
+
+    // Too long message is rejected
+    chat(game, "participant", PlayerAction::Message, Some(&"a".repeat(201)));
+    assert_eq!(game.chat.len(), 2);
+
+    // Muted opponent's messages are not delivered
+    chat(game, "creator", PlayerAction::Mute, None);
+    chat(game, "participant", PlayerAction::Message, Some("muted"));
+    let events = drain_events(creator_rx);
+    assert!(!events.iter().any(|res| matches!(res.value, ResponseValue::Chat(_))));
+
+    // History replayed on resume leaves out muted opponent too
+    assert!(game.disconnect_user("creator", 0));
+    let (resumed_tx, mut resumed_rx) = mpsc::unbounded_channel();
+    let token = game.creator.token.clone();
+    assert!(game.resume_user(&token, Box::new(ChannelAgent::new(resumed_tx, None))).is_some());
+    let events = drain_events(&mut resumed_rx);
+    if let Some(ResponseValue::ChatHistory(history)) = events.iter().map(|res| &res.value).find(|value| matches!(value, ResponseValue::ChatHistory(_))) {
+        assert_eq!(history.len(), 1);
+        assert!(history.iter().all(|msg| msg.seat == Seat::Creator));
+    } else {
+        panic!("Chat history was not replayed");
+    }
+}
+
+#[test]
+fn protocol_test() {
+    assert_eq!(ProtocolVersion::parse("1.1"), Some(ProtocolVersion { major: 1, minor: 1 }));
+    assert_eq!(ProtocolVersion::parse("1"), Some(ProtocolVersion { major: 1, minor: 0 }));
+    assert_eq!(ProtocolVersion::parse("one"), None);
+
+    let hello = ClientHello { version: Some("2.0".to_string()), capabilities: None, format: None, auth: None, account: None };
+    assert!(hello.client_info().is_err());
+
+    let message = |response_type| ServerResponse::new(response_type, ResponseValue::Message(String::new()));
+    let state = |state| ServerResponse::new(ResponseType::State, ResponseValue::State((state, String::new())));
+    let spectator_bet = ServerResponse::new(
+        ResponseType::BetResult,
+        ResponseValue::SpectatorBet(SpectatorBet { creator_action: PlayerAction::Check, participant_action: PlayerAction::Check, total_bet: 2 })
+    );
+
+    // Legacy client only gets responses of first protocol
+    // even when newer value is carried by older response type
+    let legacy = ClientHello::default().client_info().unwrap();
+    assert!(legacy.accepts(&state(GameState::Flop)));
+    assert!(!legacy.accepts(&state(GameState::Lobby)));
+    assert!(!legacy.accepts(&spectator_bet));
+    assert!(!legacy.accepts(&message(ResponseType::Deadline)));
+
+    // Legacy client gets a single game unless mode is given
+    let option = CreateOption { bot: None, mode: None, stakes: None, private: None, password: None };
+    assert_eq!(option.rules(&legacy, Rules::default()).unwrap().mode, GameMode::Single);
+
+    // Capabilities limit feature specific responses
+    let hello = ClientHello { version: Some("1.1".to_string()), capabilities: Some("chat, resume".to_string()), format: None, auth: None, account: None };
+    let client = hello.client_info().unwrap();
+    assert!(client.accepts(&message(ResponseType::Chat)));
+    assert!(client.accepts(&message(ResponseType::Token)));
+    assert!(client.accepts(&message(ResponseType::Hello)));
+    assert!(!client.accepts(&message(ResponseType::Deadline)));
+    assert!(!client.accepts(&state(GameState::Lobby)));
+    assert!(!client.accepts(&spectator_bet));
+    assert_eq!(option.rules(&client, Rules::default()).unwrap().mode, GameMode::Single);
+
+    // Client with every feature gets default rules
+    let current = ClientHello { version: Some("1.1".to_string()), capabilities: None, format: None, auth: None, account: None }.client_info().unwrap();
+    assert!(current.accepts(&state(GameState::Lobby)));
+    assert!(current.accepts(&spectator_bet));
+    assert_eq!(option.rules(&current, Rules::default()).unwrap(), Rules::default());
+}
+
+#[test]
+fn schema_test() {
+    // Run `card_server schema` after changing protocol so that clients get new models
+    for (name, schema) in schemas() {
+        let committed = std::fs::read_to_string(format!("schema/{}.json", name)).expect("Missing schema file");
+        let generated = serde_json::to_string_pretty(&schema).unwrap() + "\n";
+        assert!(committed == generated, "Schema {} is outdated", name);
+    }
+
+    // Every response type has a sample
+    let samples = samples();
+    assert_eq!(samples["ServerResponse"].as_object().unwrap().len(), 26);
+}
+
+#[test]
+fn wire_format_test() {
+    let res = ServerResponse::new(ResponseType::Raise, ResponseValue::Raise(2));
+    let req = UserRequest {
+        state_id: "state".to_string(),
+        action: PlayerAction::Call,
+        value: Some(2),
+        text: None,
+        emote: None,
+    };
+
+    for format in [WireFormat::Json, WireFormat::Msgpack, WireFormat::Cbor].iter() {
+        let hello = ClientHello { version: Some("1.1".to_string()), capabilities: None, format: Some(*format), auth: None, account: None };
+        let client = hello.client_info().unwrap();
+
+        // Same models go through every format
+        let msg = client.encode(&res);
+        assert_eq!(msg.is_binary(), *format != WireFormat::Json);
+        let decoded: ServerResponse = client.decode(&msg).unwrap();
+        assert!(matches!(decoded.value, ResponseValue::Raise(2)));
+
+        let decoded: UserRequest = client.decode(&client.encode(&req)).unwrap();
+        assert_eq!(decoded.action, PlayerAction::Call);
+
+        // Rejected client still gets error in its own format
+        let hello = ClientHello { version: Some("2.0".to_string()), capabilities: None, format: Some(*format), auth: None, account: None };
+        assert!(hello.client_info().is_err());
+        assert_eq!(hello.format().encode(&res).is_binary(), *format != WireFormat::Json);
+    }
+}
+
+#[test]
+fn config_test() {
+    // Missing sections and keys keep defaults
+    let config = ServerConfig::parse(r#"
+        [server]
+        port = 4000
+        cors_origins = ["https://example.com"]
+
+        [rules]
+        mode = "single"
+
+        [timers]
+        bet = 15
+    "#).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.addr().to_string(), "127.0.0.1:4000");
+    assert_eq!(config.default_rules().unwrap(), Rules { mode: GameMode::Single, stakes: 1 });
+    assert_eq!(config.room().timers, Timers { bet: 15, ..Timers::default() });
+    assert_eq!(config.heartbeat().interval, HeartbeatConfig::default().interval);
+    assert!(!config.metrics.enabled);
+    assert!(ServerConfig::parse("[server]\nhost = \"0.0.0.0\"").is_err());
+
+    // Every key can be overridden from environment
+    let vars = [
+        ("CARD_SERVER_BIND", "0.0.0.0"),
+        ("CARD_SERVER_CORS_ORIGINS", "https://a.example, http://localhost:8080"),
+        ("CARD_ROOMS_MAX_ROOMS", "10"),
+        ("CARD_HEARTBEAT_INTERVAL", "5"),
+        ("CARD_OUTBOX_POLICY", "disconnect"),
+        ("CARD_METRICS_ENABLED", "true"),
+        ("CARD_LOG_ACCESS", "true"),
+    ].iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<HashMap<String, String>>();
+    let config = config.with_env(&vars).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.addr().to_string(), "0.0.0.0:4000");
+    assert_eq!(config.server.cors_origins, vec!["https://a.example", "http://localhost:8080"]);
+    assert_eq!(config.room().max_rooms, 10);
+    assert_eq!(config.heartbeat().interval.as_secs(), 5);
+    assert_eq!(config.outbox().policy, SlowClientPolicy::Disconnect);
+    assert!(config.metrics.enabled);
+    assert!(config.log.access);
+
+    let invalid = |key: &str, value: &str| {
+        let vars = [(key.to_string(), value.to_string())].iter().cloned().collect();
+        ServerConfig::default().with_env(&vars).and_then(|config| config.validate())
+    };
+    assert!(invalid("CARD_SERVER_PORT", "port").is_err());
+    assert!(invalid("CARD_OUTBOX_POLICY", "wait").is_err());
+    assert!(invalid("CARD_RULES_STAKES", "100").is_err());
+    assert!(invalid("CARD_TIMERS_BET", "0").is_err());
+    assert!(invalid("CARD_SERVER_CORS_ORIGINS", "example.com").is_err());
+    assert!(invalid("CARD_SERVER_CORS_ORIGINS", "https://example.com/path").is_err());
+    assert!(invalid("CARD_TLS_CERT", "cert.pem").is_err());
+    assert!(invalid("CARD_SERVER_PORT", "8080").is_ok());
+}
+
+#[tokio::test]
+async fn room_config_test() {
+    // Rooms are given timers of config
+    let timers = Timers { bet: 5, time_bank: 7, ..Timers::default() };
+    let (creator_tx, _creator_rx) = mpsc::unbounded_channel();
+    let (part_tx, _part_rx) = mpsc::unbounded_channel();
+    let (commands_tx, mut commands_rx) = mpsc::unbounded_channel();
+    let mut game = Game::new("creator".to_string(), Box::new(ChannelAgent::new(creator_tx, None)), commands_tx, Rules::default(), timers);
+    game.join_game("participant".to_string(), Box::new(ChannelAgent::new(part_tx, None)));
+    game.init_game();
+    assert_eq!(game.participant.as_ref().unwrap().stat.timer.bank, 7000);
+    match commands_rx.try_recv() {
+        Ok(RoomCommand::Timer { duration, .. }) => assert!(duration.as_millis() <= 12_000 && duration.as_millis() > 11_000),
+        _ => panic!("Turn timer was not started"),
+    }
+
+    // Rooms are not opened over limit
+    let rooms = Rooms::new(RoomConfig { max_rooms: 1, ..RoomConfig::default() }, Storage::open(":memory:").unwrap());
+    let lobby = Lobby::new(RwLock::new(HashMap::new()));
+    assert!(!rooms.is_full());
+    let (creator_tx, _creator_rx) = mpsc::unbounded_channel();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    let connection = Connection::new("creator".to_string(), "room".to_string(), Box::new(ChannelAgent::new(creator_tx, None)), commands_tx, rooms.config().rules, rooms.config().timers);
+    rooms.open(connection, commands_rx, &lobby);
+    assert!(rooms.is_full());
+}
+
+// Self signed certificate and key for localhost in pem
+fn self_signed() -> (String, String) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    (cert.serialize_pem().unwrap(), cert.serialize_private_key_pem())
+}
+
+// Response head, and body when connection is closed by server.
+// Client only trusts given certificate.
+async fn tls_request(addr: std::net::SocketAddr, trusted: &str, request: &str) -> std::io::Result<String> {
+    let mut config = ClientConfig::new();
+    config.root_store.add_pem_file(&mut trusted.as_bytes()).unwrap();
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream).await?;
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = vec![];
+    let mut buf = [0u8; 1024];
+    loop {
+        let read = stream.read(&mut buf).await?;
+        response.extend_from_slice(&buf[..read]);
+        let text = String::from_utf8_lossy(&response);
+        if read == 0 || text.starts_with("HTTP/1.1 101") && text.contains("\r\n\r\n") {
+            return Ok(text.to_string());
+        }
+    }
+}
+
+#[tokio::test]
+async fn tls_test() {
+    let dir = std::env::temp_dir().join(format!("card_server_tls_{}", uuid::Uuid::new_v4().to_simple()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    let (first_cert, first_key) = self_signed();
+    std::fs::write(&cert_path, &first_cert).unwrap();
+    std::fs::write(&key_path, &first_key).unwrap();
+
+    let certs = TlsCerts::load(&cert_path, &key_path).unwrap();
+    let rooms = Rooms::default();
+    let lobby = Lobby::new(RwLock::new(HashMap::new()));
+    let outboxes = Outboxes::default();
+    let accounts = Accounts::new(Arc::new(Storage::open(":memory:").unwrap()), b"secret", 60);
+    let filter = routes::create_room(&rooms, &lobby, HeartbeatConfig::default(), &outboxes, &accounts)
+        .or(routes::get_rooms(&rooms));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::task::spawn(tls::serve(filter, listener, certs.clone()));
+
+    let get = "GET /rooms HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let response = tls_request(addr, &first_cert, get).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("[]"));
+
+    // Websocket is upgraded over tls
+    let upgrade = "GET /create?version=1.1 HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+    let response = tls_request(addr, &first_cert, upgrade).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 101"));
+
+    // Renewed certificate is used only after reload
+    let (second_cert, second_key) = self_signed();
+    std::fs::write(&cert_path, &second_cert).unwrap();
+    std::fs::write(&key_path, &second_key).unwrap();
+    assert!(tls_request(addr, &second_cert, get).await.is_err());
+    certs.reload().unwrap();
+    assert!(tls_request(addr, &second_cert, get).await.unwrap().starts_with("HTTP/1.1 200"));
+    assert!(tls_request(addr, &first_cert, get).await.is_err());
+
+    // Broken renewal keeps previous certificate
+    std::fs::write(&key_path, "not a key").unwrap();
+    assert!(certs.reload().is_err());
+    assert!(TlsCerts::load(&cert_path, &key_path).is_err());
+    assert!(tls_request(addr, &second_cert, get).await.unwrap().starts_with("HTTP/1.1 200"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn credentials(name: &str, password: &str) -> Credentials {
+    Credentials {
+        name: name.to_string(),
+        password: password.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn accounts_test() {
+    let store = Arc::new(Storage::open(":memory:").unwrap());
+    let accounts = Accounts::new(store.clone(), b"secret", 60);
+
+    assert!(matches!(accounts.register(&credentials("ab", "password")), Err(AuthError::Invalid(_))));
+    assert!(matches!(accounts.register(&credentials("a b c", "password")), Err(AuthError::Invalid(_))));
+    assert!(matches!(accounts.register(&credentials("alice", "short")), Err(AuthError::Invalid(_))));
+
+    let session = accounts.register(&credentials("alice", "correct horse")).unwrap();
+    assert_eq!(session.account.name, "alice");
+    assert_eq!(accounts.verify(&session.token), Ok(session.account.clone()));
+    assert_eq!(accounts.register(&credentials("Alice", "correct horse")).unwrap_err(), AuthError::NameTaken);
+
+    // Only a salted hash is stored
+    let (_, hash) = store.find_by_name("alice").unwrap().unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    assert!(!hash.contains("correct horse"));
+
+    // Account id is stable across logins
+    let login = accounts.login(&credentials("ALICE", "correct horse")).unwrap();
+    assert_eq!(login.account, session.account);
+    assert_eq!(accounts.login(&credentials("alice", "wrong horse")).unwrap_err(), AuthError::WrongCredentials);
+    assert_eq!(accounts.login(&credentials("bob", "correct horse")).unwrap_err(), AuthError::WrongCredentials);
+    // Unknown name is checked against dummy hash and never matches
+    assert_eq!(accounts.login(&credentials("bob", "not a password")).unwrap_err(), AuthError::WrongCredentials);
+
+    // Tokens signed with other secret, tampered or expired are refused
+    let other = Accounts::new(Arc::new(Storage::open(":memory:").unwrap()), b"other", 60);
+    assert!(other.verify(&session.token).is_err());
+    let (payload, signature) = session.token.split_at(session.token.find('.').unwrap());
+    let forged = base64::encode_config(
+        serde_json::to_vec(&serde_json::json!({ "id": "admin", "name": "admin", "exp": u64::MAX })).unwrap(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    assert!(accounts.verify(&format!("{}{}", forged, signature)).is_err());
+    assert!(accounts.verify(payload).is_err());
+    let expired = Accounts::new(store.clone(), b"secret", 0).issue(session.account.clone());
+    assert!(accounts.verify(&expired.token).is_err());
+
+    // Routes reply with status of the error
+    let register = routes::register(&accounts);
+    let res = warp::test::request().method("POST").path("/register")
+        .json(&serde_json::json!({ "name": "bob", "password": "hunter22" }))
+        .reply(&register).await;
+    assert_eq!(res.status(), 201);
+    let res = warp::test::request().method("POST").path("/register")
+        .json(&serde_json::json!({ "name": "bob", "password": "hunter22" }))
+        .reply(&register).await;
+    assert_eq!(res.status(), 409);
+    let login = routes::login(&accounts);
+    let res = warp::test::request().method("POST").path("/login")
+        .json(&serde_json::json!({ "name": "bob", "password": "hunter23" }))
+        .reply(&login).await;
+    assert_eq!(res.status(), 401);
+    assert!(String::from_utf8_lossy(res.body()).contains("Wrong name or password"));
+
+    // Browser client may post json credentials from allowed origin
+    let config = ServerConfig::parse("[server]\ncors_origins = [\"https://example.com\"]").unwrap();
+    let res = warp::test::request().method("OPTIONS").path("/login")
+        .header("origin", "https://example.com")
+        .header("access-control-request-method", "POST")
+        .header("access-control-request-headers", "content-type")
+        .reply(&login.clone().with(config.cors())).await;
+    assert_eq!(res.status(), 200);
+
+    // Auth token is verified when websocket connects
+    let hello = |path: String| {
+        let accounts = accounts.clone();
+        async move {
+            warp::test::request().path(&path)
+                .filter(&client_hello(accounts)).await.unwrap()
+                .client_info()
+        }
+    };
+    let client = hello(format!("/create?version=1.1&auth={}", session.token)).await.unwrap();
+    assert_eq!(client.account, Some(session.account));
+    assert!(hello("/create?version=1.1".to_string()).await.unwrap().account.is_none());
+    assert_eq!(hello("/create?version=1.1&auth=forged".to_string()).await.unwrap_err(), "Invalid auth token");
+}
+
+#[test]
+fn account_seat_test() {
+    // Seat carries account of its client and opponent sees the name
+    let account = Account { id: "alice-id".to_string(), name: "alice".to_string() };
+    let client = ClientHello {
+        version: Some("1.1".to_string()),
+        capabilities: None,
+        format: None,
+        auth: Some("token".to_string()),
+        account: Some(Ok(account.clone())),
+    }.client_info().unwrap();
+    assert!(ClientHello { auth: Some("token".to_string()), ..ClientHello::default() }.client_info().is_err());
+    assert!(ClientHello { auth: Some("token".to_string()), account: Some(Err("Invalid auth token".to_string())), ..ClientHello::default() }.client_info().is_err());
+
+    let (creator_tx, _creator_rx) = outbox();
+    let (part_tx, mut part_rx) = outbox();
+    let guest = ClientHello { version: Some("1.1".to_string()), ..ClientHello::default() }.client_info().unwrap();
+    let (commands_tx, _commands_rx) = mpsc::unbounded_channel();
+    let mut game = Game::new("creator".to_string(), Box::new(WebSocketAgent::new(creator_tx, client)), commands_tx, Rules::default(), Timers::default());
+    game.join_game("participant".to_string(), Box::new(WebSocketAgent::new(part_tx, guest.clone())));
+    game.init_game();
+
+    assert_eq!(game.creator.account, Some(account));
+    assert_eq!(game.creator.name(), "alice");
+    assert_eq!(game.participant.as_ref().unwrap().name(), "Guest");
+    let env = received(&mut part_rx).iter()
+        .map(|msg| guest.decode::<serde_json::Value>(msg).unwrap())
+        .find(|res| res["response_type"] == "Env")
+        .unwrap();
+    assert_eq!(env["value"]["Env"]["opp_name"], "alice");
+}
+
+#[tokio::test]
+async fn storage_test() {
+    let dir = std::env::temp_dir().join(format!("card_server_storage_{}", uuid::Uuid::new_v4().to_simple()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("card_server.db").to_string_lossy().to_string();
+    let storage = Storage::open(&path).unwrap();
+    let alice = storage.create("alice", "hash").unwrap();
+    let bob = storage.create("bob", "hash").unwrap();
+    assert_eq!(storage.create("Alice", "hash").unwrap_err(), AuthError::NameTaken);
+
+    // Game sends its hands to the room when someone has lost every hp
+    let mut table = Table::started();
+    let game = &mut table.game;
+    game.creator.account = Some(alice.clone());
+    game.participant.as_mut().unwrap().account = Some(bob.clone());
+    replay(game, "creator", PlayerAction::Raise, Some(1));
+    replay(game, "participant", PlayerAction::Fold, None);
+    game.next_state();
+    game.participant.as_mut().unwrap().stat.hp = 1;
+    replay(game, "creator", PlayerAction::Raise, Some(1));
+    replay(game, "participant", PlayerAction::Fold, None);
+    let mut record = None;
+    while let Ok(command) = table.commands_rx.try_recv() {
+        if let RoomCommand::Record(command) = command {
+            record = Some(command);
+        }
+    }
+    let record = record.expect("Finished game was not recorded");
+    assert!(record.creator_won);
+    assert_eq!(record.game, 1);
+    assert_eq!(record.participant_hp, 0);
+    assert_eq!(record.hands.len(), 2);
+    assert_eq!(record.hands[0].creator_won, Some(true));
+    assert!(record.hands[0].participant_fold && record.hands[0].participant_comb.is_none());
+    assert!(record.hands[0].creator_comb.is_some());
+    assert!(table.game.hands.is_empty());
+
+    // Bob has also won a later game against a guest
+    storage.record_match("room", &record).unwrap();
+    let guest_match = crate::storage::MatchRecord {
+        creator: None,
+        creator_won: false,
+        hands: vec![],
+        finished: record.finished + 1,
+        ..record.clone()
+    };
+    storage.record_match("other", &guest_match).unwrap();
+
+    // History survives restart
+    drop(storage);
+    let storage = Storage::open(&path).unwrap();
+    let profile = storage.profile(&alice.id).unwrap().unwrap();
+    assert_eq!((profile.matches, profile.wins, profile.losses), (1, 1, 0));
+    assert_eq!((profile.hands, profile.hands_won, profile.hands_lost), (2, 2, 0));
+    let profile = storage.profile(&bob.id).unwrap().unwrap();
+    assert_eq!(profile.account, bob);
+    assert_eq!((profile.matches, profile.wins, profile.losses), (2, 1, 1));
+    assert_eq!((profile.hands, profile.hands_won, profile.hands_lost), (2, 0, 2));
+    assert!(storage.profile("nobody").unwrap().is_none());
+
+    // Matches are told from the side of player, latest first
+    let matches = storage.recent_matches(&bob.id, 10).unwrap().unwrap();
+    assert_eq!(matches.len(), 2);
+    assert_eq!((matches[0].room_id.as_str(), matches[0].opp_name.as_str(), matches[0].opp_id.as_ref()), ("other", "Guest", None));
+    assert!(matches[0].win);
+    assert_eq!((matches[1].opp_name.as_str(), matches[1].opp_id.as_ref()), ("alice", Some(&alice.id)));
+    assert!(!matches[1].win);
+    assert_eq!((matches[1].hp, matches[1].opp_hp), (0, record.creator_hp));
+    let hand = &matches[1].hands[0];
+    assert_eq!(hand.win, Some(false));
+    assert!(hand.fold && hand.comb.is_none() && hand.opp_comb.is_some());
+    assert_eq!(storage.recent_matches(&bob.id, 1).unwrap().unwrap().len(), 1);
+    assert!(storage.recent_matches("nobody", 10).unwrap().is_none());
+
+    // Routes reply with profile and matches, or not found
+    let filter = routes::get_profile(&storage).or(routes::get_matches(&storage));
+    let res = warp::test::request().path(&format!("/players/{}", alice.id)).reply(&filter).await;
+    assert_eq!(res.status(), 200);
+    let profile: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(profile["account"]["name"], "alice");
+    assert_eq!(profile["wins"], 1);
+    let res = warp::test::request().path(&format!("/players/{}/matches?limit=1", bob.id)).reply(&filter).await;
+    assert_eq!(res.status(), 200);
+    let matches: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(matches.as_array().unwrap().len(), 1);
+    assert_eq!(matches[0]["mode"], serde_json::to_value(record.mode).unwrap());
+    assert_eq!(warp::test::request().path("/players/nobody").reply(&filter).await.status(), 404);
+    assert_eq!(warp::test::request().path("/players/nobody/matches").reply(&filter).await.status(), 404);
+
+    drop(storage);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
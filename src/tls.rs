@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use hyper::server::conn::Http;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile;
+use warp::{Filter, Rejection, Reply};
+
+// Certificate and key which are read again when server gets SIGHUP,
+// so renewed certificate is used without dropping open games.
+#[derive(Clone)]
+pub struct TlsCerts {
+    cert: PathBuf,
+    key: PathBuf,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsCerts {
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, String> {
+        Ok(Self {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            config: Arc::new(RwLock::new(Arc::new(server_config(cert, key)?))),
+        })
+    }
+
+    // Previous certificate is kept when new one can't be read,
+    // thus a broken renewal doesn't take server down.
+    pub fn reload(&self) -> Result<(), String> {
+        let config = server_config(&self.cert, &self.key)?;
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+        Ok(())
+    }
+
+    // Connections which are already open keep certificate they were accepted with
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap_or_else(PoisonError::into_inner).clone())
+    }
+}
+
+fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig, String> {
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|err| format!("Couldn't read {} : {}", path.display(), err))
+    };
+
+    let certs = pemfile::certs(&mut &read(cert)?[..])
+        .map_err(|_| format!("Invalid certificate {}", cert.display()))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", cert.display()));
+    }
+    let key = private_key(&read(key)?)
+        .ok_or_else(|| format!("No private key in {}", key.display()))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)
+        .map_err(|err| format!("Invalid certificate or key : {}", err))?;
+    Ok(config)
+}
+
+// Key is either PKCS#8 or PKCS#1 RSA pem
+fn private_key(pem: &[u8]) -> Option<PrivateKey> {
+    pemfile::pkcs8_private_keys(&mut &pem[..]).ok()
+        .and_then(|mut keys| keys.pop())
+        .or_else(|| pemfile::rsa_private_keys(&mut &pem[..]).ok().and_then(|mut keys| keys.pop()))
+}
+
+pub async fn reload_on_hangup(certs: TlsCerts) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            eprintln!("Couldn't listen to SIGHUP, certificate won't be reloaded : {}", err);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match certs.reload() {
+            Ok(()) => eprintln!("Reloaded TLS certificate"),
+            Err(err) => eprintln!("Kept previous TLS certificate : {}", err),
+        }
+    }
+}
+
+// Same as warp's server except that handshake uses current certificate.
+// Upgrades are enabled for websockets.
+pub async fn serve<F>(filter: F, mut listener: TcpListener, certs: TlsCerts)
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let service = warp::service(filter);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                eprintln!("Couldn't accept connection : {}", err);
+                continue;
+            }
+        };
+        let acceptor = certs.acceptor();
+        let service = service.clone();
+        tokio::task::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("TLS handshake failed : {}", err);
+                    return;
+                }
+            };
+            if let Err(err) = Http::new().serve_connection(stream, service).with_upgrades().await {
+                eprintln!("Connection error : {}", err);
+            }
+        });
+    }
+}